@@ -0,0 +1,227 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::PathBuf,
+};
+use tiny_http::Request;
+
+// a credential, either inline or read from a file on every request so that it can be rotated
+// without restarting the exporter
+#[derive(Debug, Clone)]
+pub enum Secret {
+    Value(String),
+    File(PathBuf),
+}
+
+impl Secret {
+    // an empty secret is an error, it would let in any request without credentials
+    fn read(&self) -> Result<String> {
+        let secret = match self {
+            Secret::Value(value) => value.clone(),
+            // editors and `echo` leave a trailing newline in secret files
+            Secret::File(path) => std::fs::read_to_string(path)
+                .map(|s| s.trim_end_matches(['\r', '\n']).to_owned())
+                .map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!("Can't read secret {}: {e}", path.display()),
+                    )
+                })?,
+        };
+
+        if secret.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Empty secret"));
+        }
+        Ok(secret)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Authentication {
+    Basic { username: String, password: Secret },
+    Bearer(Secret),
+}
+
+impl Authentication {
+    // value of the WWW-Authenticate header sent with a 401
+    pub(crate) fn challenge(&self) -> &'static str {
+        match self {
+            Authentication::Basic { .. } => "Basic realm=\"metrics\"",
+            Authentication::Bearer(_) => "Bearer realm=\"metrics\"",
+        }
+    }
+
+    pub(crate) fn check(&self, request: &Request) -> Result<bool> {
+        self.check_header(
+            request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.as_str()),
+        )
+    }
+
+    fn check_header(&self, authorization: Option<&str>) -> Result<bool> {
+        let Some(authorization) = authorization else {
+            return Ok(false);
+        };
+
+        let (scheme, credentials) = authorization
+            .trim()
+            .split_once(' ')
+            .unwrap_or((authorization, ""));
+        let credentials = credentials.trim();
+
+        match self {
+            Authentication::Basic { username, password } => {
+                if !scheme.eq_ignore_ascii_case("basic") {
+                    return Ok(false);
+                }
+
+                let decoded = STANDARD.decode(credentials).map_err(|e| {
+                    Error::new(ErrorKind::InvalidData, format!("Invalid basic auth: {e}"))
+                })?;
+                let expected = format!("{username}:{}", password.read()?);
+                Ok(constant_time_eq(&decoded, expected.as_bytes()))
+            }
+            Authentication::Bearer(token) => {
+                if !scheme.eq_ignore_ascii_case("bearer") {
+                    return Ok(false);
+                }
+
+                Ok(constant_time_eq(
+                    credentials.as_bytes(),
+                    token.read()?.as_bytes(),
+                ))
+            }
+        }
+    }
+}
+
+// do not leak the length of the matching prefix through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{constant_time_eq, Authentication, Secret};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    fn basic(username: &str, password: Secret) -> Authentication {
+        Authentication::Basic {
+            username: username.to_owned(),
+            password,
+        }
+    }
+
+    fn basic_header(credentials: &str) -> String {
+        format!("Basic {}", STANDARD.encode(credentials))
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let auth = basic("user", Secret::Value("pass".to_owned()));
+        assert!(auth.check_header(Some(&basic_header("user:pass"))).unwrap());
+        assert!(auth
+            .check_header(Some(&format!("basic  {}", STANDARD.encode("user:pass"))))
+            .unwrap());
+        assert!(!auth
+            .check_header(Some(&basic_header("user:wrong")))
+            .unwrap());
+        assert!(!auth
+            .check_header(Some(&basic_header("other:pass")))
+            .unwrap());
+        assert!(!auth.check_header(None).unwrap());
+    }
+
+    #[test]
+    fn password_with_colon() {
+        // only the first colon separates the username
+        let auth = basic("user", Secret::Value("pa:ss:".to_owned()));
+        assert!(auth
+            .check_header(Some(&basic_header("user:pa:ss:")))
+            .unwrap());
+        assert!(!auth.check_header(Some(&basic_header("user:pa"))).unwrap());
+        assert!(!auth
+            .check_header(Some(&basic_header("user:pa:ss")))
+            .unwrap());
+    }
+
+    #[test]
+    fn wrong_scheme() {
+        let auth = basic("user", Secret::Value("pass".to_owned()));
+        let credentials = STANDARD.encode("user:pass");
+        assert!(!auth
+            .check_header(Some(&format!("Bearer {credentials}")))
+            .unwrap());
+        assert!(!auth.check_header(Some(&credentials)).unwrap());
+
+        let auth = Authentication::Bearer(Secret::Value("token".to_owned()));
+        assert!(auth.check_header(Some("Bearer token")).unwrap());
+        assert!(auth.check_header(Some("bearer token ")).unwrap());
+        assert!(!auth.check_header(Some("Basic token")).unwrap());
+        assert!(!auth.check_header(Some("Bearer")).unwrap());
+        assert!(!auth.check_header(Some("Bearer tok")).unwrap());
+    }
+
+    #[test]
+    fn malformed_base64() {
+        let auth = basic("user", Secret::Value("pass".to_owned()));
+        assert!(auth.check_header(Some("Basic not*base64")).is_err());
+        assert!(auth.check_header(Some("Basic dXNlcjpwYXNz=")).is_err());
+    }
+
+    #[test]
+    fn length_mismatch() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokens"));
+        assert!(!constant_time_eq(b"tokens", b"token"));
+        assert!(!constant_time_eq(b"", b"token"));
+        assert!(!constant_time_eq(b"tokem", b"token"));
+    }
+
+    #[test]
+    fn file_secret() {
+        let path =
+            std::env::temp_dir().join(format!("metrics_lockfree_secret_{}", std::process::id()));
+        std::fs::write(&path, "token\r\n").unwrap();
+
+        let auth = Authentication::Bearer(Secret::File(path.clone()));
+        assert!(auth.check_header(Some("Bearer token")).unwrap());
+
+        // read again on every request
+        std::fs::write(&path, "rotated\n").unwrap();
+        assert!(auth.check_header(Some("Bearer rotated")).unwrap());
+        assert!(!auth.check_header(Some("Bearer token")).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(auth.check_header(Some("Bearer rotated")).is_err());
+    }
+
+    #[test]
+    fn empty_secret() {
+        let auth = Authentication::Bearer(Secret::Value(String::new()));
+        assert!(auth.check_header(Some("Bearer")).is_err());
+        assert!(auth.check_header(Some("Bearer token")).is_err());
+        assert!(!auth.check_header(None).unwrap());
+
+        let auth = basic("user", Secret::Value(String::new()));
+        assert!(auth.check_header(Some(&basic_header("user:"))).is_err());
+
+        // a file left with only a newline
+        let path = std::env::temp_dir().join(format!(
+            "metrics_lockfree_empty_secret_{}",
+            std::process::id()
+        ));
+        std::fs::write(&path, "\n").unwrap();
+        let auth = Authentication::Bearer(Secret::File(path.clone()));
+        assert!(auth.check_header(Some("Bearer")).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
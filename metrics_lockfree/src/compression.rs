@@ -0,0 +1,136 @@
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use std::io::{Result, Write};
+use tiny_http::Request;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ContentEncoding {
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    // pick the encoding from the Accept-Encoding header
+    pub(crate) fn negotiate(request: &Request) -> Option<Self> {
+        let values: Vec<&str> = request
+            .headers()
+            .iter()
+            .filter(|h| h.field.equiv("Accept-Encoding"))
+            .map(|h| h.value.as_str())
+            .collect();
+        Self::from_accept_encoding(&values)
+    }
+
+    // the coding with the highest quality wins, gzip on a tie. a coding listed by name takes
+    // its own quality and not the one of "*", so "gzip;q=0, *" refuses gzip. codings with a zero
+    // or invalid quality are refused
+    fn from_accept_encoding(values: &[&str]) -> Option<Self> {
+        let codings: Vec<(String, Option<f32>)> = values
+            .iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|coding| {
+                let mut params = coding.split(';').map(str::trim);
+                let name = params.next()?.to_ascii_lowercase();
+                if name.is_empty() {
+                    return None;
+                }
+                let q = params
+                    .find_map(|p| {
+                        let (k, v) = p.split_once('=')?;
+                        k.trim().eq_ignore_ascii_case("q").then(|| v.trim())
+                    })
+                    .map_or(Some(1.0), |q| {
+                        q.parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))
+                    });
+                Some((name, q))
+            })
+            .collect();
+
+        let quality = |name: &str| {
+            let find = |n: &str| codings.iter().find(|(c, _)| c == n).map(|(_, q)| *q);
+            find(name)
+                .or_else(|| find("*"))
+                .flatten()
+                .filter(|q| *q > 0.0)
+        };
+
+        match (quality("gzip"), quality("deflate")) {
+            (Some(gzip), Some(deflate)) if deflate > gzip => Some(ContentEncoding::Deflate),
+            (Some(_), _) => Some(ContentEncoding::Gzip),
+            (None, Some(_)) => Some(ContentEncoding::Deflate),
+            (None, None) => None,
+        }
+    }
+
+    pub(crate) fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            // http "deflate" is the zlib format
+            ContentEncoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ContentEncoding::{self, Deflate, Gzip};
+
+    fn negotiate(value: &str) -> Option<ContentEncoding> {
+        ContentEncoding::from_accept_encoding(&[value])
+    }
+
+    #[test]
+    fn codings() {
+        assert_eq!(negotiate(""), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("gzip"), Some(Gzip));
+        assert_eq!(negotiate("deflate"), Some(Deflate));
+        assert_eq!(negotiate("br, deflate, gzip"), Some(Gzip));
+        assert_eq!(negotiate("GZIP"), Some(Gzip));
+        assert_eq!(
+            ContentEncoding::from_accept_encoding(&["br", "deflate"]),
+            Some(Deflate)
+        );
+    }
+
+    #[test]
+    fn q_values() {
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(negotiate("gzip; q=0.000"), None);
+        assert_eq!(negotiate("gzip;Q=0, deflate"), Some(Deflate));
+        assert_eq!(negotiate("gzip;q=0.5, deflate;q=0.8"), Some(Deflate));
+        assert_eq!(negotiate("gzip;q=0.8, deflate;q=0.8"), Some(Gzip));
+        assert_eq!(negotiate("gzip;q=1.0, deflate"), Some(Gzip));
+        // an invalid quality refuses the coding
+        assert_eq!(negotiate("gzip;q=abc"), None);
+        assert_eq!(negotiate("gzip;q=2, deflate"), Some(Deflate));
+    }
+
+    #[test]
+    fn wildcard() {
+        assert_eq!(negotiate("*"), Some(Gzip));
+        assert_eq!(negotiate("*;q=0"), None);
+        assert_eq!(negotiate("gzip;q=0, *"), Some(Deflate));
+        assert_eq!(negotiate("*, gzip;q=0"), Some(Deflate));
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0, *"), None);
+        assert_eq!(negotiate("*;q=0, gzip"), Some(Gzip));
+        assert_eq!(negotiate("deflate, *;q=0.5"), Some(Deflate));
+    }
+}
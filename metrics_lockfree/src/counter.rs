@@ -1,6 +1,13 @@
+use crate::types::Tags;
 use ahash::{HashMap, HashMapExt};
 //use std::collections::HashMap;
-use std::{cell::UnsafeCell, marker::PhantomPinned, pin::Pin};
+use std::{
+    any::Any,
+    cell::UnsafeCell,
+    marker::PhantomPinned,
+    pin::Pin,
+    sync::{Arc, RwLock},
+};
 
 // counter
 pub struct CounterCell<const MAX_TAGS: usize> {
@@ -41,6 +48,15 @@ impl<const MAX_TAGS: usize> CounterPin<MAX_TAGS> {
         self.values.get(idx)
     }
 
+    // add the values of another cell, used to keep the totals of a retired thread handle. no
+    // counter handle may point to self
+    pub fn merge(&mut self, other: &Self) {
+        let values = self.as_mut_ptr();
+        for idx in 0..MAX_TAGS {
+            unsafe { (*values)[idx] += other.get(idx) };
+        }
+    }
+
     fn as_mut_ptr(&self) -> *mut [u64; MAX_TAGS] {
         self.values.as_ref().as_mut_ptr()
     }
@@ -61,13 +77,36 @@ impl<const MAX_TAGS: usize> Default for CounterPin<MAX_TAGS> {
 
 pub type AllocTagsFn = fn(&[(String, String)]) -> Option<usize>;
 
+// where a counter gets ids for tags it has not seen yet
+enum TagsAllocator {
+    Fn(AllocTagsFn),
+    // tags shared by the thread handles of a struct built in a registry
+    Shared(Arc<RwLock<Tags>>),
+}
+
+impl TagsAllocator {
+    fn alloc(&self, tags: &[(String, String)]) -> Option<usize> {
+        match self {
+            TagsAllocator::Fn(f) => f(tags),
+            TagsAllocator::Shared(shared) => {
+                if let Some(id) = shared.read().ok()?.get(tags) {
+                    return Some(id);
+                }
+                shared.write().ok()?.insert(tags)
+            }
+        }
+    }
+}
+
 pub struct Counter<const MAX_TAGS: usize = 1> {
     // ptr to list of values, indexed by tag id
     values: *mut [u64; MAX_TAGS],
     // local cache for mapping tags to id
     tags: HashMap<Vec<(String, String)>, usize>,
     // function to call to create a new tag, when it is not in local cache
-    global_allocator: Option<AllocTagsFn>,
+    global_allocator: Option<TagsAllocator>,
+    // owner of the values, kept alive as long as the handle points to them
+    keep_alive: Option<Arc<dyn Any + Send + Sync>>,
 }
 
 impl<const MAX_TAGS: usize> Counter<MAX_TAGS> {
@@ -99,8 +138,8 @@ impl<const MAX_TAGS: usize> Counter<MAX_TAGS> {
             return Some(*id);
         }
 
-        if let Some(allocator) = self.global_allocator {
-            if let Some(id) = (allocator)(tags) {
+        if let Some(allocator) = &self.global_allocator {
+            if let Some(id) = allocator.alloc(tags) {
                 self.tags.insert(tags.to_vec(), id);
                 return Some(id);
             }
@@ -111,7 +150,17 @@ impl<const MAX_TAGS: usize> Counter<MAX_TAGS> {
     }
 
     pub fn set_fn(mut self, f: AllocTagsFn) -> Self {
-        self.global_allocator = Some(f);
+        self.global_allocator = Some(TagsAllocator::Fn(f));
+        self
+    }
+
+    pub fn set_tags(mut self, tags: Arc<RwLock<Tags>>) -> Self {
+        self.global_allocator = Some(TagsAllocator::Shared(tags));
+        self
+    }
+
+    pub fn keep_alive(mut self, owner: Arc<dyn Any + Send + Sync>) -> Self {
+        self.keep_alive = Some(owner);
         self
     }
 }
@@ -122,6 +171,7 @@ impl<const MAX_TAGS: usize> From<&mut CounterPin<MAX_TAGS>> for Counter<MAX_TAGS
             values: cell.as_mut_ptr(),
             tags: HashMap::new(),
             global_allocator: None,
+            keep_alive: None,
         }
     }
 }
@@ -0,0 +1,73 @@
+use crate::{registry::Registry, snapshot::MetricsSnapshot};
+use std::{io::Result, sync::Arc};
+
+// reads a registry as per interval deltas: each collection gives the counters increase since the
+// previous one and the gauges as they are. only snapshots are read, the per thread values are
+// never written to
+pub struct DeltaReader {
+    registry: Arc<Registry>,
+    // totals returned by the last collection. the counters of a derived struct keep the totals
+    // of its dropped thread handles, so they only go down on a restart. a metric that is gone
+    // from the registry is forgotten with it, and starts again from zero if it comes back
+    previous: MetricsSnapshot,
+}
+
+impl DeltaReader {
+    // the first collection gives the counters totals since they were registered
+    pub fn new(registry: Arc<Registry>) -> Self {
+        Self {
+            registry,
+            previous: MetricsSnapshot::default(),
+        }
+    }
+
+    // a counter total lower than the previous one is taken as a restart, all of it is the
+    // increase
+    pub fn collect(&mut self) -> Result<MetricsSnapshot> {
+        let current = self.registry.snapshot()?;
+        let delta = current.delta(&self.previous);
+        self.previous = current;
+        Ok(delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{snapshot::family, types::MetricType};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    fn value(snapshot: &MetricsSnapshot) -> f64 {
+        snapshot
+            .family("requests")
+            .unwrap()
+            .sample(&[])
+            .unwrap()
+            .value
+    }
+
+    #[test]
+    fn counter_reset() {
+        let registry = Arc::new(Registry::new());
+        let total = Arc::new(AtomicU64::new(5));
+        let counter = total.clone();
+        registry
+            .register(move || {
+                let total = counter.load(Ordering::Relaxed) as f64;
+                vec![family("requests", MetricType::Counter, &[(&[], total)]).to_metric_family()]
+            })
+            .unwrap();
+
+        let mut delta = DeltaReader::new(registry);
+        assert_eq!(value(&delta.collect().unwrap()), 5.0);
+        total.store(8, Ordering::Relaxed);
+        assert_eq!(value(&delta.collect().unwrap()), 3.0);
+        assert_eq!(value(&delta.collect().unwrap()), 0.0);
+
+        // restarted from zero, all of it is the increase and never a negative delta
+        total.store(2, Ordering::Relaxed);
+        assert_eq!(value(&delta.collect().unwrap()), 2.0);
+        total.store(4, Ordering::Relaxed);
+        assert_eq!(value(&delta.collect().unwrap()), 2.0);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{snapshot::MetricsSnapshot, types::MetricType};
+use std::io::{Error, ErrorKind, Result, Write};
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// an exposition format, served by the exporter on its own route
+pub trait Encoder: Send + Sync {
+    fn encode(&self, snapshot: &MetricsSnapshot, writer: &mut dyn Write) -> Result<()>;
+
+    // value of the Content-Type header of the response
+    fn content_type(&self) -> &str;
+}
+
+// prometheus text format
+#[derive(Debug, Default)]
+pub struct TextEncoder;
+
+impl Encoder for TextEncoder {
+    fn encode(&self, snapshot: &MetricsSnapshot, writer: &mut dyn Write) -> Result<()> {
+        prometheus_encode(&prometheus::TextEncoder::new(), snapshot, writer)
+    }
+
+    fn content_type(&self) -> &str {
+        prometheus::TEXT_FORMAT
+    }
+}
+
+// prometheus delimited protobuf format
+#[derive(Debug, Default)]
+pub struct ProtobufEncoder;
+
+impl Encoder for ProtobufEncoder {
+    fn encode(&self, snapshot: &MetricsSnapshot, writer: &mut dyn Write) -> Result<()> {
+        prometheus_encode(&prometheus::ProtobufEncoder::new(), snapshot, writer)
+    }
+
+    fn content_type(&self) -> &str {
+        prometheus::PROTOBUF_FORMAT
+    }
+}
+
+// openmetrics 1.0 text format. counter samples get the _total suffix the family name doesn't
+// have, and the exposition ends with "# EOF"
+#[derive(Debug, Default)]
+pub struct OpenMetricsEncoder;
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode(&self, snapshot: &MetricsSnapshot, writer: &mut dyn Write) -> Result<()> {
+        let mut buffer = String::new();
+        for family in snapshot.families.iter().filter(|f| !f.samples.is_empty()) {
+            let (name, sample_name, metric_type) = match family.metric_type {
+                MetricType::Counter => {
+                    let name = family.name.strip_suffix("_total").unwrap_or(&family.name);
+                    (name, format!("{name}_total"), "counter")
+                }
+                MetricType::Gauge => (family.name.as_str(), family.name.clone(), "gauge"),
+            };
+
+            buffer.push_str(&format!("# TYPE {name} {metric_type}\n"));
+            if !family.help.is_empty() {
+                buffer.push_str(&format!(
+                    "# HELP {name} {}\n",
+                    openmetrics_escape(&family.help)
+                ));
+            }
+            for sample in &family.samples {
+                buffer.push_str(&sample_name);
+                if !sample.labels.is_empty() {
+                    let labels: Vec<String> = sample
+                        .labels
+                        .iter()
+                        .map(|(k, v)| format!("{k}=\"{}\"", openmetrics_escape(v)))
+                        .collect();
+                    buffer.push_str(&format!("{{{}}}", labels.join(",")));
+                }
+                buffer.push_str(&format!(" {}\n", openmetrics_value(sample.value)));
+            }
+        }
+        buffer.push_str("# EOF\n");
+
+        writer.write_all(buffer.as_bytes())
+    }
+
+    fn content_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+fn openmetrics_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn openmetrics_value(value: f64) -> String {
+    if value.is_nan() {
+        String::from("NaN")
+    } else if value.is_infinite() {
+        String::from(if value > 0.0 { "+Inf" } else { "-Inf" })
+    } else {
+        value.to_string()
+    }
+}
+
+fn prometheus_encode<E: prometheus::Encoder>(
+    encoder: &E,
+    snapshot: &MetricsSnapshot,
+    mut writer: &mut dyn Write,
+) -> Result<()> {
+    encoder
+        .encode(&snapshot.to_metric_families(), &mut writer)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Can't encode metrics: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::family;
+
+    #[test]
+    fn openmetrics_text() {
+        let mut requests = family(
+            "requests_total",
+            MetricType::Counter,
+            &[(&[], 1.0), (&[("path", "/a\"b\\")], 4.0)],
+        );
+        requests.help = String::from("line one\nline two");
+        let snapshot = MetricsSnapshot {
+            families: vec![
+                requests,
+                family("errors", MetricType::Counter, &[(&[], 0.0)]),
+                family("temperature", MetricType::Gauge, &[(&[], -1.5)]),
+                family("ratio", MetricType::Gauge, &[(&[], f64::NAN)]),
+                family("empty", MetricType::Gauge, &[]),
+            ],
+        };
+
+        let mut buffer = vec![];
+        OpenMetricsEncoder.encode(&snapshot, &mut buffer).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "# TYPE requests counter\n\
+             # HELP requests line one\\nline two\n\
+             requests_total 1\n\
+             requests_total{path=\"/a\\\"b\\\\\"} 4\n\
+             # TYPE errors counter\n\
+             errors_total 0\n\
+             # TYPE temperature gauge\n\
+             temperature -1.5\n\
+             # TYPE ratio gauge\n\
+             ratio NaN\n\
+             # EOF\n"
+        );
+    }
+}
@@ -1,37 +1,234 @@
-use prometheus::{Encoder, TextEncoder};
+#[cfg(feature = "compression")]
+use crate::compression::ContentEncoding;
+use crate::{
+    auth::{Authentication, Secret},
+    encoder::{Encoder, OpenMetricsEncoder, ProtobufEncoder, TextEncoder},
+    json::JsonEncoder,
+    registry::{RegistrationHandle, Registry},
+    self_metrics::{SelfMetrics, SelfMetricsSet},
+};
+#[cfg(target_os = "linux")]
+use std::net::Shutdown;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(unix)]
+use std::os::unix::{
+    fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    net::{UnixListener, UnixStream},
+};
+#[cfg(any(unix, feature = "tls"))]
+use std::path::{Path, PathBuf};
 use std::{
-    net::SocketAddr,
-    sync::{LazyLock, RwLock},
-    thread,
+    io::Cursor,
+    mem::ManuallyDrop,
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{net::TcpStream, os::fd::OwnedFd};
+use tiny_http::{
+    Header, ListenAddr, Listener, Request, Response, Server as HTTPServer, StatusCode,
 };
-use tiny_http::{Header, Request, Response, Server as HTTPServer, StatusCode};
 
 use std::io::{Error, ErrorKind, Result};
 
-type ExportFn = fn() -> Vec<prometheus::proto::MetricFamily>;
+pub use crate::registry::ExportFn;
+
+pub struct Exporter;
+
+// what to answer to requests on unknown urls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotFoundPolicy {
+    // 301 redirect to the metrics endpoint
+    Redirect,
+    // plain 404
+    NotFound,
+}
+
+// where the http server listens
+enum Binding {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    #[cfg(target_os = "linux")]
+    UnixAbstract(Vec<u8>),
+}
+
+pub struct ExporterBuilder {
+    binding: Binding,
+    registry: Arc<Registry>,
+    #[cfg(unix)]
+    unix_permissions: Option<u32>,
+    endpoint: String,
+    encoders: Vec<(String, Box<dyn Encoder>)>,
+    not_found_policy: NotFoundPolicy,
+    worker_threads: usize,
+    max_concurrent_scrapes: usize,
+    queue_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    thread_name: String,
+    ready: bool,
+    self_metrics: bool,
+    authentication: Vec<Authentication>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsFiles>,
+}
+
+// pem files of the certificate chain and private key served over https
+#[cfg(feature = "tls")]
+struct TlsFiles {
+    certificate: PathBuf,
+    private_key: PathBuf,
+}
 
-static METRICS_EXPORT_FN: LazyLock<RwLock<Vec<ExportFn>>> =
-    LazyLock::new(|| RwLock::new(Vec::new()));
+// running exporter, the server is stopped when the handle is dropped
+#[must_use = "the exporter is stopped when its handle is dropped"]
+pub struct ExporterHandle {
+    server: ManuallyDrop<Arc<HTTPServer>>,
+    dispatcher: Option<JoinHandle<()>>,
+    workers: Vec<JoinHandle<()>>,
+    listen_addr: ListenAddr,
+    ready: Arc<AtomicBool>,
+    // tiny_http closes the listening socket from its accept thread, some time after the server
+    // is dropped, and can't close abstract unix sockets at all. a copy of the listener is shut
+    // down to stop accepting connections right away
+    #[cfg(target_os = "linux")]
+    listener: Option<OwnedFd>,
+    // unix socket moved after it was bound, removed on drop
+    #[cfg(unix)]
+    socket_path: Option<PathBuf>,
+}
 
-pub struct Exporter {
-    binding: SocketAddr,
+// routing table and scrape limits shared by the dispatcher and worker threads
+struct Router {
+    registry: Arc<Registry>,
     endpoint: String,
+    encoders: Vec<(String, Box<dyn Encoder>)>,
+    not_found_policy: NotFoundPolicy,
+    ready: Arc<AtomicBool>,
+    authentication: Vec<Authentication>,
+    max_concurrent_scrapes: usize,
+    queue_timeout: Option<Duration>,
+    self_metrics: bool,
+    // counted by the worker and control threads of this exporter
+    metrics: Arc<SelfMetricsSet>,
+    // scrapes being answered or waiting for a worker
+    in_flight: AtomicUsize,
 }
 
+// metrics routes, answered by the worker threads
+enum ScrapeRoute {
+    // encoder picked from the accept header
+    Negotiated,
+    // index in the router encoders
+    Encoder(usize),
+}
+
+struct ScrapeJob {
+    request: Request,
+    route: ScrapeRoute,
+    queued_at: Instant,
+}
+
+// requests answered by the control thread
+enum ControlJob {
+    // health, readiness, index and unknown routes
+    Route(Request),
+    // scrapes over the concurrency limit, rejected with a 503
+    TooManyScrapes(Request),
+}
+
+const HEALTH_ENDPOINT: &str = "/healthz";
+const READY_ENDPOINT: &str = "/readyz";
+
 impl Exporter {
-    pub fn builder(binding: SocketAddr) -> Self {
-        Self {
+    pub fn builder(binding: SocketAddr) -> ExporterBuilder {
+        Self::builder_with(Binding::Tcp(binding))
+    }
+
+    // serve the metrics over a unix socket bound at the given path
+    #[cfg(unix)]
+    pub fn builder_unix<P: AsRef<Path>>(path: P) -> ExporterBuilder {
+        Self::builder_with(Binding::Unix(path.as_ref().to_owned()))
+    }
+
+    // serve the metrics over a unix socket in the abstract namespace, the name must not include
+    // the leading nul byte.
+    // tiny_http panics when a server bound to an abstract socket is dropped, so on shutdown the
+    // socket is closed but the server itself is leaked: a few small allocations per exporter.
+    // prefer a path socket when exporters are started and stopped repeatedly
+    #[cfg(target_os = "linux")]
+    pub fn builder_unix_abstract(name: &[u8]) -> ExporterBuilder {
+        Self::builder_with(Binding::UnixAbstract(name.to_owned()))
+    }
+
+    fn builder_with(binding: Binding) -> ExporterBuilder {
+        ExporterBuilder {
             binding,
+            registry: Registry::global(),
+            #[cfg(unix)]
+            unix_permissions: None,
             endpoint: String::from("/metrics"),
+            encoders: vec![],
+            not_found_policy: NotFoundPolicy::NotFound,
+            worker_threads: 4,
+            max_concurrent_scrapes: 8,
+            queue_timeout: Some(Duration::from_secs(10)),
+            write_timeout: Some(Duration::from_secs(30)),
+            thread_name: String::from("metrics-http"),
+            ready: true,
+            self_metrics: true,
+            authentication: vec![],
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
-    pub fn register(f: ExportFn) {
-        if let Ok(mut metrics) = METRICS_EXPORT_FN.write() {
-            metrics.push(f);
-        } else {
-            // todo
-        }
+    // shorthands for the global registry
+    pub fn register<F>(f: F) -> Result<()>
+    where
+        F: Fn() -> Vec<prometheus::proto::MetricFamily> + Send + Sync + 'static,
+    {
+        Registry::global().register(f)
+    }
+
+    pub fn register_scoped<F>(f: F) -> Result<RegistrationHandle>
+    where
+        F: Fn() -> Vec<prometheus::proto::MetricFamily> + Send + Sync + 'static,
+    {
+        Registry::global().register_scoped(f)
+    }
+
+    pub fn register_struct<F, T>(name: &'static str, f: F, threads: T) -> Result<()>
+    where
+        F: Fn() -> Vec<prometheus::proto::MetricFamily> + Send + Sync + 'static,
+        T: Fn() -> usize + Send + Sync + 'static,
+    {
+        Registry::global().register_struct(name, f, threads)
+    }
+
+    pub fn gauge_fn<F>(name: &str, help: &str, labels: &[(String, String)], f: F) -> Result<()>
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        Registry::global().gauge_fn(name, help, labels, f)
+    }
+
+    pub fn gauge_fn_scoped<F>(
+        name: &str,
+        help: &str,
+        labels: &[(String, String)],
+        f: F,
+    ) -> Result<RegistrationHandle>
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        Registry::global().gauge_fn_scoped(name, help, labels, f)
     }
 
     /*
@@ -39,58 +236,141 @@ impl Exporter {
         let exporter = metrics_lockfree::Exporter::start(binding).unwrap();
     */
 
-    pub fn start(binding: SocketAddr) -> Result<()> {
-        let exporter = Self::builder(binding);
+    pub fn start(binding: SocketAddr) -> Result<ExporterHandle> {
+        Self::builder(binding).start()
+    }
 
-        let server = HTTPServer::http(exporter.binding).map_err(|e| {
-            Error::new(
-                ErrorKind::ConnectionAborted,
-                format!("Can't start http server: {e}"),
-            )
-        })?;
+    // the response is sent by the caller, so that a failure to collect and a failure to send
+    // can be told apart
+    fn metrics_response(
+        request: &Request,
+        registry: &Registry,
+        encoder: &dyn Encoder,
+        self_metrics: Option<&SelfMetricsSet>,
+    ) -> Result<Response<Cursor<Vec<u8>>>> {
+        let snapshot = registry.collect(self_metrics)?;
 
-        let endpoint = exporter.endpoint.clone();
+        let mut buffer = vec![];
+        encoder.encode(&snapshot, &mut buffer)?;
 
-        let _th = thread::spawn(move || {
-            for request in server.incoming_requests() {
-                let _err = if request.url() == endpoint {
-                    Self::handler_metrics(request)
-                } else {
-                    Self::handler_redirect(request, &endpoint)
-                };
-            }
+        #[cfg(feature = "compression")]
+        let content_encoding = ContentEncoding::negotiate(request);
+        #[cfg(feature = "compression")]
+        if let Some(content_encoding) = content_encoding {
+            buffer = content_encoding.compress(&buffer)?;
+        }
+
+        let mut response = Response::from_data(buffer).with_status_code(StatusCode(200));
+        response.add_header(Header {
+            field: "Content-Type"
+                .parse()
+                .expect("can not parse content type header field. this should never fail"),
+            value: ascii::AsciiString::from_ascii(encoder.content_type()).map_err(|e| {
+                Error::new(ErrorKind::InvalidData, format!("Invalid content type: {e}"))
+            })?,
         });
 
-        Ok(())
-    }
+        #[cfg(feature = "compression")]
+        {
+            response.add_header(Header {
+                field: "Vary"
+                    .parse()
+                    .expect("can not parse vary header field. this should never fail"),
+                value: "Accept-Encoding"
+                    .parse()
+                    .expect("can not parse header value. this should never fail"),
+            });
 
-    fn handler_metrics(request: Request) -> Result<()> {
-        let mut buffer = vec![];
-        let encoder = TextEncoder::new();
-        let mut metric_families = vec![];
-
-        // fill metric families vector calling all metrics functions
-        match METRICS_EXPORT_FN.read() {
-            Ok(metrics) => {
-                metrics.iter().for_each(|f| {
-                    let m = (f)();
-                    m.into_iter().for_each(|m| metric_families.push(m));
+            if let Some(content_encoding) = content_encoding {
+                response.add_header(Header {
+                    field: "Content-Encoding".parse().expect(
+                        "can not parse content encoding header field. this should never fail",
+                    ),
+                    value: content_encoding
+                        .as_str()
+                        .parse()
+                        .expect("can not parse header value. this should never fail"),
                 });
+            }
+        }
 
-                encoder.encode(&metric_families, &mut buffer).map_err(|e| {
-                    Error::new(ErrorKind::InvalidData, format!("Can't encode metrics: {e}"))
-                })?;
+        Ok(response)
+    }
 
-                let response = Response::from_data(buffer).with_status_code(StatusCode(200));
-                request
-                    .respond(response)
-                    .map_err(|e| Error::new(e.kind(), format!("Can't send response: {e}")))
+    // pick the encoder from the Accept header
+    fn negotiate_encoder(request: &Request) -> &'static dyn Encoder {
+        let values: Vec<&str> = request
+            .headers()
+            .iter()
+            .filter(|h| h.field.equiv("Accept"))
+            .map(|h| h.value.as_str())
+            .collect();
+        Self::encoder_from_accept(&values)
+    }
+
+    // prometheus asks for the delimited protobuf format in its accept header when it supports
+    // it, then for openmetrics. internal tooling can ask for json. the format with the highest
+    // quality wins, in that order on a tie. a format listed by name takes its own quality, the
+    // text format takes the one of "text/*" or "*/*" otherwise. a zero or invalid quality
+    // refuses the format, and we fall back to the text format when nothing else is accepted
+    fn encoder_from_accept(values: &[&str]) -> &'static dyn Encoder {
+        let encoders: [&'static dyn Encoder; 4] = [
+            &ProtobufEncoder,
+            &OpenMetricsEncoder,
+            &JsonEncoder,
+            &TextEncoder,
+        ];
+        let mut named: [Option<f32>; 4] = [None; 4];
+        let mut wildcard: Option<f32> = None;
+
+        for media_range in values.iter().flat_map(|value| value.split(',')) {
+            let mut params = media_range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let params: Vec<(String, &str)> = params
+                .filter_map(|p| {
+                    let (k, v) = p.split_once('=')?;
+                    Some((k.trim().to_ascii_lowercase(), v.trim()))
+                })
+                .collect();
+            let param = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| *v);
+            let q = param("q").map_or(1.0, |q| {
+                q.parse::<f32>()
+                    .ok()
+                    .filter(|q| (0.0..=1.0).contains(q))
+                    .unwrap_or(0.0)
+            });
+
+            let quality = match media_type.as_str() {
+                "application/vnd.google.protobuf"
+                    if param("proto") == Some("io.prometheus.client.MetricFamily")
+                        && param("encoding") == Some("delimited") =>
+                {
+                    &mut named[0]
+                }
+                "application/openmetrics-text"
+                    if param("version").is_none_or(|version| version == "1.0.0") =>
+                {
+                    &mut named[1]
+                }
+                "application/json" => &mut named[2],
+                "text/plain" => &mut named[3],
+                "text/*" | "*/*" => &mut wildcard,
+                _ => continue,
+            };
+            *quality = Some(quality.map_or(q, |previous| previous.max(q)));
+        }
+        named[3] = named[3].or(wildcard);
+
+        // the first of the highest qualities
+        let mut best: Option<(usize, f32)> = None;
+        for (i, q) in named.iter().enumerate() {
+            if let Some(q) = q.filter(|q| *q > 0.0) {
+                if best.is_none_or(|(_, best)| q > best) {
+                    best = Some((i, q));
+                }
             }
-            Err(e) => Err(Error::new(
-                ErrorKind::InvalidData,
-                format!("Poison error: {e}"),
-            )),
         }
+        best.map_or(&TextEncoder, |(i, _)| encoders[i])
     }
 
     fn handler_redirect(request: Request, endpoint: &str) -> Result<()> {
@@ -108,4 +388,1135 @@ impl Exporter {
             .respond(response)
             .map_err(|e| Error::new(e.kind(), format!("Can't send redirect: {e}")))
     }
+
+    fn handler_index(request: Request, paths: &[&str]) -> Result<()> {
+        let mut body =
+            String::from("<html><head><title>metrics_lockfree exporter</title></head><body><ul>\n");
+        paths.iter().for_each(|path| {
+            let path = html_escape(path);
+            body.push_str(&format!("<li><a href=\"{path}\">{path}</a></li>\n"));
+        });
+        body.push_str("</ul></body></html>\n");
+
+        let response = Response::from_string(body)
+            .with_status_code(200)
+            .with_header(Header {
+                field: "Content-Type"
+                    .parse()
+                    .expect("can not parse content type header field. this should never fail"),
+                value: "text/html; charset=utf-8"
+                    .parse()
+                    .expect("can not parse header value. this should never fail"),
+            });
+
+        request
+            .respond(response)
+            .map_err(|e| Error::new(e.kind(), format!("Can't send response: {e}")))
+    }
+
+    fn handler_status(request: Request, ok: bool, message: &str) -> Result<()> {
+        let response = if ok {
+            Response::from_string(format!("{message}\n")).with_status_code(200)
+        } else {
+            Response::from_string(format!("not {message}\n")).with_status_code(503)
+        };
+
+        request
+            .respond(response)
+            .map_err(|e| Error::new(e.kind(), format!("Can't send response: {e}")))
+    }
+
+    fn handler_unauthorized(request: Request, challenges: &[&str]) -> Result<()> {
+        let mut response = Response::from_string("unauthorized\n").with_status_code(401);
+        challenges.iter().for_each(|challenge| {
+            response.add_header(Header {
+                field: "WWW-Authenticate"
+                    .parse()
+                    .expect("can not parse authenticate header field. this should never fail"),
+                value: challenge
+                    .parse()
+                    .expect("can not parse header value. this should never fail"),
+            })
+        });
+
+        request
+            .respond(response)
+            .map_err(|e| Error::new(e.kind(), format!("Can't send response: {e}")))
+    }
+
+    fn handler_unavailable(request: Request, message: &str) -> Result<()> {
+        let response = Response::from_string(format!("{message}\n")).with_status_code(503);
+
+        request
+            .respond(response)
+            .map_err(|e| Error::new(e.kind(), format!("Can't send response: {e}")))
+    }
+
+    fn handler_not_found(request: Request) -> Result<()> {
+        let response = Response::from_string("not found\n").with_status_code(404);
+
+        request
+            .respond(response)
+            .map_err(|e| Error::new(e.kind(), format!("Can't send response: {e}")))
+    }
+}
+
+impl ExporterBuilder {
+    // serve the metrics of this registry instead of the global one
+    pub fn registry(mut self, registry: Arc<Registry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.to_owned();
+        self
+    }
+
+    // serve the snapshot with a custom encoder on the given path, which can't be the metrics
+    // endpoint
+    pub fn encoder<E: Encoder + 'static>(mut self, path: &str, encoder: E) -> Self {
+        self.encoders.push((path.to_owned(), Box::new(encoder)));
+        self
+    }
+
+    pub fn not_found_policy(mut self, policy: NotFoundPolicy) -> Self {
+        self.not_found_policy = policy;
+        self
+    }
+
+    // require http basic auth on metrics routes, a request is accepted if it matches any of the
+    // configured credentials
+    pub fn basic_auth(mut self, username: &str, password: Secret) -> Self {
+        self.authentication.push(Authentication::Basic {
+            username: username.to_owned(),
+            password,
+        });
+        self
+    }
+
+    // require an "Authorization: Bearer <token>" header on metrics routes
+    pub fn bearer_token(mut self, token: Secret) -> Self {
+        self.authentication.push(Authentication::Bearer(token));
+        self
+    }
+
+    // serve over https with the given pem encoded certificate chain and private key. the files
+    // are read when the exporter starts.
+    // client certificates are not requested: tiny_http builds its rustls config without client
+    // auth, so mutual tls needs a tls terminator in front of the exporter
+    #[cfg(feature = "tls")]
+    pub fn tls<P: AsRef<Path>>(mut self, certificate: P, private_key: P) -> Self {
+        self.tls = Some(TlsFiles {
+            certificate: certificate.as_ref().to_owned(),
+            private_key: private_key.as_ref().to_owned(),
+        });
+        self
+    }
+
+    // file mode of the unix socket, for example 0o660 to restrict it to the group of the
+    // process. ignored for tcp and abstract sockets
+    #[cfg(unix)]
+    pub fn unix_permissions(mut self, mode: u32) -> Self {
+        self.unix_permissions = Some(mode);
+        self
+    }
+
+    // expose the exporter own metrics (scrape durations and status codes, registered structs,
+    // thread handles and label sets) along with the registered ones
+    pub fn self_metrics(mut self, enabled: bool) -> Self {
+        self.self_metrics = enabled;
+        self
+    }
+
+    // initial state of the readiness flag served on /readyz, see ExporterHandle::set_ready
+    pub fn ready(mut self, ready: bool) -> Self {
+        self.ready = ready;
+        self
+    }
+
+    // number of threads answering scrapes, at least one. other routes are answered by a
+    // separate control thread so that they are not stuck behind slow scrapes
+    pub fn worker_threads(mut self, n: usize) -> Self {
+        self.worker_threads = n.max(1);
+        self
+    }
+
+    // scrapes being answered or waiting for a worker, requests over this limit get a 503
+    // instead of queueing
+    pub fn max_concurrent_scrapes(mut self, n: usize) -> Self {
+        self.max_concurrent_scrapes = n.max(1);
+        self
+    }
+
+    // scrapes that waited longer than this for a worker get a 503, the scraper has most likely
+    // given up on them already. None to never expire them. only the time spent in the queue is
+    // bounded: the collection itself isn't interrupted, and sending the response is bounded by
+    // the write timeout
+    pub fn queue_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.queue_timeout = timeout;
+        self
+    }
+
+    // how long a write to a client may block before its connection is dropped, so that a
+    // stalled scraper holds a worker for a bounded time. only applies to tcp on unix, where
+    // accepted connections inherit it from the listening socket. there is no read timeout: the
+    // accept of the listening socket would obey it too, and the server would stop once idle
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    // the dispatcher thread is named "<name>", scrape workers "<name>-<index>" and the control
+    // thread "<name>-control"
+    pub fn thread_name(mut self, name: &str) -> Self {
+        self.thread_name = name.to_owned();
+        self
+    }
+
+    pub fn start(self) -> Result<ExporterHandle> {
+        // the endpoint is matched first, such an encoder would never be used
+        if let Some((path, _)) = self
+            .encoders
+            .iter()
+            .find(|(path, _)| *path == self.endpoint)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Encoder path {path} is the metrics endpoint"),
+            ));
+        }
+
+        let listener = self.bind()?;
+
+        #[cfg(target_os = "linux")]
+        let listener_copy = match &listener {
+            Listener::Tcp(l) => OwnedFd::from(l.try_clone()?),
+            Listener::Unix(l) => OwnedFd::from(l.try_clone()?),
+        };
+
+        #[cfg(feature = "tls")]
+        let ssl_config = match &self.tls {
+            Some(tls) => {
+                let read = |path: &PathBuf| {
+                    std::fs::read(path).map_err(|e| {
+                        Error::new(e.kind(), format!("Can't read {}: {e}", path.display()))
+                    })
+                };
+                Some(tiny_http::SslConfig {
+                    certificate: read(&tls.certificate)?,
+                    private_key: read(&tls.private_key)?,
+                })
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "tls"))]
+        let ssl_config = None;
+
+        let server = HTTPServer::from_listener(listener, ssl_config).map_err(|e| {
+            Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("Can't start http server: {e}"),
+            )
+        })?;
+        let server = Arc::new(server);
+        let mut listen_addr = server.server_addr();
+        // a socket bound with a mode was moved, tiny_http only knows the path it was bound at
+        #[cfg(unix)]
+        let socket_path = match (&self.binding, self.unix_permissions) {
+            (Binding::Unix(path), Some(_)) => {
+                let addr = std::os::unix::net::SocketAddr::from_pathname(path)?;
+                listen_addr = ListenAddr::Unix(addr);
+                Some(path.clone())
+            }
+            _ => None,
+        };
+
+        // user encoders are matched first so they can override the json route
+        let mut encoders = self.encoders;
+        encoders.push((format!("{}.json", self.endpoint), Box::new(JsonEncoder)));
+
+        let ready = Arc::new(AtomicBool::new(self.ready));
+
+        let router = Arc::new(Router {
+            registry: self.registry,
+            endpoint: self.endpoint,
+            encoders,
+            not_found_policy: self.not_found_policy,
+            ready: ready.clone(),
+            authentication: self.authentication,
+            max_concurrent_scrapes: self.max_concurrent_scrapes,
+            queue_timeout: self.queue_timeout,
+            self_metrics: self.self_metrics,
+            metrics: Arc::new(SelfMetricsSet::new()),
+            in_flight: AtomicUsize::new(0),
+        });
+
+        let (sender, receiver) = mpsc::channel::<ScrapeJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let mut workers = vec![];
+        for i in 0..self.worker_threads {
+            let receiver = receiver.clone();
+            let router = router.clone();
+
+            let th = thread::Builder::new()
+                .name(format!("{}-{i}", self.thread_name))
+                .spawn(move || {
+                    let mut metrics = router.metrics.handle();
+                    loop {
+                        // the channel is closed when the dispatcher exits
+                        let job = match receiver.lock() {
+                            Ok(receiver) => receiver.recv(),
+                            Err(_) => break,
+                        };
+                        match job {
+                            Ok(job) => {
+                                let _err = router.handle_scrape(job, &mut metrics);
+                                router.in_flight.fetch_sub(1, Ordering::AcqRel);
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                })?;
+            workers.push(th);
+        }
+
+        let (control_sender, control_receiver) = mpsc::channel::<ControlJob>();
+        {
+            let router = router.clone();
+
+            let th = thread::Builder::new()
+                .name(format!("{}-control", self.thread_name))
+                .spawn(move || {
+                    let mut metrics = router.metrics.handle();
+                    // the channel is closed when the dispatcher exits
+                    for job in control_receiver {
+                        let _err = router.handle_control(job, &mut metrics);
+                    }
+                })?;
+            workers.push(th);
+        }
+
+        let dispatcher = {
+            let server = server.clone();
+            let router = router.clone();
+
+            thread::Builder::new()
+                .name(self.thread_name.clone())
+                .spawn(move || {
+                    for request in server.incoming_requests() {
+                        let _err = router.dispatch(request, &sender, &control_sender);
+                    }
+                })?
+        };
+
+        Ok(ExporterHandle {
+            server: ManuallyDrop::new(server),
+            dispatcher: Some(dispatcher),
+            workers,
+            listen_addr,
+            ready,
+            #[cfg(target_os = "linux")]
+            listener: Some(listener_copy),
+            #[cfg(unix)]
+            socket_path,
+        })
+    }
+
+    fn bind(&self) -> Result<Listener> {
+        match &self.binding {
+            Binding::Tcp(addr) => TcpListener::bind(addr)
+                .and_then(|listener| set_write_timeout(listener, self.write_timeout))
+                .map(Listener::from),
+            #[cfg(unix)]
+            Binding::Unix(path) => {
+                remove_stale_socket(path)?;
+                match self.unix_permissions {
+                    Some(mode) => bind_with_mode(path, mode),
+                    None => UnixListener::bind(path),
+                }
+                .map(Listener::from)
+            }
+            #[cfg(target_os = "linux")]
+            Binding::UnixAbstract(name) => {
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                UnixListener::bind_addr(&addr).map(Listener::from)
+            }
+        }
+        .map_err(|e| Error::new(e.kind(), format!("Can't bind http server: {e}")))
+    }
+}
+
+// tiny_http gives no access to the accepted sockets, but they inherit the send timeout of the
+// listening socket
+#[cfg(unix)]
+fn set_write_timeout(listener: TcpListener, timeout: Option<Duration>) -> Result<TcpListener> {
+    let socket = TcpStream::from(OwnedFd::from(listener));
+    socket.set_write_timeout(timeout)?;
+    Ok(TcpListener::from(OwnedFd::from(socket)))
+}
+
+#[cfg(not(unix))]
+fn set_write_timeout(listener: TcpListener, _timeout: Option<Duration>) -> Result<TcpListener> {
+    Ok(listener)
+}
+
+// the socket is bound in a directory only the process can enter, and moved to the path once its
+// mode is set. bound at the path directly, it would be reachable with the permissions of the
+// umask until then
+#[cfg(unix)]
+fn bind_with_mode(path: &Path, mode: u32) -> Result<UnixListener> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Socket path has no file name"))?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    // left behind by a process that died
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let private = dir.join("s");
+    let listener = UnixListener::bind(&private).and_then(|listener| {
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&private, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&dir);
+    listener
+}
+
+// a socket file left behind by a process that died can be replaced, but not one that is still
+// being listened on
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_err() {
+                std::fs::remove_file(path)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+impl ExporterHandle {
+    // address the server is bound to, useful when binding port 0. None for unix sockets, see
+    // unix_addr
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listen_addr.clone().to_ip()
+    }
+
+    // address of the unix socket the server is bound to, None for tcp
+    #[cfg(unix)]
+    pub fn unix_addr(&self) -> Option<std::os::unix::net::SocketAddr> {
+        self.listen_addr.clone().to_unix()
+    }
+
+    // readiness reported on /readyz, an application can flip it while warming up or draining
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    // block until the dispatcher and worker threads exit
+    pub fn join(mut self) {
+        self.join_threads();
+    }
+
+    // stop answering requests and wait for the worker threads. on linux new connections are
+    // refused once it returns, the socket itself is closed a moment later by the accept thread
+    // of tiny_http. elsewhere the socket is closed asynchronously
+    pub fn shutdown(self) {
+        // everything is done on drop
+    }
+
+    fn join_threads(&mut self) {
+        // workers exit once the dispatcher is gone and the scrapes it queued are answered
+        if let Some(dispatcher) = self.dispatcher.take() {
+            let _ = dispatcher.join();
+        }
+        self.workers.drain(..).for_each(|th| {
+            let _ = th.join();
+        });
+    }
+}
+
+impl Drop for ExporterHandle {
+    fn drop(&mut self) {
+        // wake up the dispatcher, requests already queued in the server are dispatched first
+        if self.dispatcher.is_some() {
+            self.server.unblock();
+        }
+        self.join_threads();
+
+        // shutting the listening socket down refuses new connections and makes the accept
+        // thread fail and exit, which closes the socket
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(listener) = self.listener.take() {
+                let _ = UnixStream::from(listener).shutdown(Shutdown::Both);
+            }
+
+            // dropping a tiny_http server bound to an abstract socket panics, it is leaked
+            if let ListenAddr::Unix(addr) = &self.listen_addr {
+                if addr.as_pathname().is_none() {
+                    return;
+                }
+            }
+        }
+
+        // the listening socket is closed when the last reference to the server is dropped
+        // SAFETY: the server is not used anymore once the worker threads are joined
+        unsafe { ManuallyDrop::drop(&mut self.server) };
+
+        #[cfg(unix)]
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Router {
+    fn scrape_route(&self, url: &str) -> Option<ScrapeRoute> {
+        if url == self.endpoint {
+            Some(ScrapeRoute::Negotiated)
+        } else {
+            self.encoders
+                .iter()
+                .position(|(path, _)| url == path)
+                .map(ScrapeRoute::Encoder)
+        }
+    }
+
+    // the dispatcher only routes requests and never writes to a client: scrapes go to the
+    // workers, everything else to the control thread
+    fn dispatch(
+        &self,
+        request: Request,
+        workers: &mpsc::Sender<ScrapeJob>,
+        control: &mpsc::Sender<ControlJob>,
+    ) -> Result<()> {
+        let control_job = match self.scrape_route(path(&request)) {
+            None => ControlJob::Route(request),
+            Some(_) if self.in_flight.load(Ordering::Acquire) >= self.max_concurrent_scrapes => {
+                ControlJob::TooManyScrapes(request)
+            }
+            Some(route) => return self.dispatch_scrape(request, route, workers),
+        };
+
+        control.send(control_job).map_err(|_| {
+            Error::new(
+                ErrorKind::BrokenPipe,
+                "Can't dispatch request: no control thread",
+            )
+        })
+    }
+
+    fn dispatch_scrape(
+        &self,
+        request: Request,
+        route: ScrapeRoute,
+        workers: &mpsc::Sender<ScrapeJob>,
+    ) -> Result<()> {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        workers
+            .send(ScrapeJob {
+                request,
+                route,
+                queued_at: Instant::now(),
+            })
+            .map_err(|_| {
+                self.in_flight.fetch_sub(1, Ordering::AcqRel);
+                Error::new(
+                    ErrorKind::BrokenPipe,
+                    "Can't dispatch scrape: no worker left",
+                )
+            })
+    }
+
+    fn handle_scrape(&self, job: ScrapeJob, metrics: &mut SelfMetrics) -> Result<()> {
+        // secrets may be read from files, which is done here rather than on the dispatcher
+        if !self.authorized(&job.request) {
+            let res = self.handler_unauthorized(job.request);
+            return count_response(metrics, 401, res);
+        }
+
+        if self
+            .queue_timeout
+            .is_some_and(|timeout| job.queued_at.elapsed() > timeout)
+        {
+            let res = Exporter::handler_unavailable(job.request, "scrape timed out in queue");
+            return count_response(metrics, 503, res);
+        }
+
+        let encoder = match job.route {
+            ScrapeRoute::Negotiated => Exporter::negotiate_encoder(&job.request),
+            ScrapeRoute::Encoder(i) => self.encoders[i].1.as_ref(),
+        };
+
+        let start = Instant::now();
+        let response = Exporter::metrics_response(
+            &job.request,
+            &self.registry,
+            encoder,
+            self.self_metrics.then_some(&*self.metrics),
+        );
+        let res = match response {
+            Ok(response) => {
+                let res = job
+                    .request
+                    .respond(response)
+                    .map_err(|e| Error::new(e.kind(), format!("Can't send response: {e}")));
+                count_response(metrics, 200, res)
+            }
+            Err(e) => {
+                // a request dropped without a response is answered with a 500 by tiny_http
+                metrics.scrape_answered(500);
+                Err(e)
+            }
+        };
+        metrics.scrape_duration(start.elapsed());
+
+        res
+    }
+
+    fn handle_control(&self, job: ControlJob, metrics: &mut SelfMetrics) -> Result<()> {
+        match job {
+            ControlJob::Route(request) => self.handle(request),
+            ControlJob::TooManyScrapes(request) => {
+                let res = Exporter::handler_unavailable(request, "too many concurrent scrapes");
+                count_response(metrics, 503, res)
+            }
+        }
+    }
+
+    fn handle(&self, request: Request) -> Result<()> {
+        let path = path(&request);
+        if path == HEALTH_ENDPOINT {
+            Exporter::handler_status(request, true, "ok")
+        } else if path == READY_ENDPOINT {
+            Exporter::handler_status(request, self.ready.load(Ordering::Relaxed), "ready")
+        } else if path == "/" {
+            let mut paths = vec![self.endpoint.as_str()];
+            paths.extend(self.encoders.iter().map(|(path, _)| path.as_str()));
+            paths.extend([HEALTH_ENDPOINT, READY_ENDPOINT]);
+            Exporter::handler_index(request, &paths)
+        } else {
+            match self.not_found_policy {
+                NotFoundPolicy::Redirect => Exporter::handler_redirect(request, &self.endpoint),
+                NotFoundPolicy::NotFound => Exporter::handler_not_found(request),
+            }
+        }
+    }
+
+    // metrics routes are open when no authentication is configured. a secret that can't be read
+    // denies the request
+    fn authorized(&self, request: &Request) -> bool {
+        self.authentication.is_empty()
+            || self
+                .authentication
+                .iter()
+                .any(|auth| auth.check(request).unwrap_or(false))
+    }
+
+    fn handler_unauthorized(&self, request: Request) -> Result<()> {
+        let mut challenges: Vec<&str> = self
+            .authentication
+            .iter()
+            .map(Authentication::challenge)
+            .collect();
+        challenges.sort_unstable();
+        challenges.dedup();
+
+        Exporter::handler_unauthorized(request, &challenges)
+    }
+}
+
+// a response that couldn't be sent is counted as an error rather than under its status code
+fn count_response(metrics: &mut SelfMetrics, code: u16, res: Result<()>) -> Result<()> {
+    match res {
+        Ok(()) => metrics.scrape_answered(code),
+        Err(_) => metrics.scrape_failed(),
+    }
+    res
+}
+
+// routes are matched without the query string, scrapers may send parameters
+fn path(request: &Request) -> &str {
+    request.url().split('?').next().unwrap_or_default()
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoder::OPENMETRICS_FORMAT, json::JSON_FORMAT, snapshot::MetricsSnapshot};
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    // status and body of a GET answered with Connection: close
+    fn get(addr: SocketAddr, url: &str, headers: &[(&str, &str)]) -> (u16, String) {
+        get_on(TcpStream::connect(addr).unwrap(), url, headers)
+    }
+
+    fn get_on<S: Read + Write>(stream: S, url: &str, headers: &[(&str, &str)]) -> (u16, String) {
+        let (status, _, body) = get_raw_on(stream, url, headers);
+        (status, String::from_utf8(body).unwrap())
+    }
+
+    // status, lowercase header lines and body
+    fn get_raw_on<S: Read + Write>(
+        mut stream: S,
+        url: &str,
+        headers: &[(&str, &str)],
+    ) -> (u16, String, Vec<u8>) {
+        let mut request = format!("GET {url} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n");
+        headers
+            .iter()
+            .for_each(|(k, v)| request.push_str(&format!("{k}: {v}\r\n")));
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).unwrap();
+
+        // the connection may be closed without a tls close_notify
+        let mut response = vec![];
+        if let Err(e) = stream.read_to_end(&mut response) {
+            assert_eq!(e.kind(), ErrorKind::UnexpectedEof, "{e}");
+        }
+        let end = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .unwrap_or(response.len());
+        let head = String::from_utf8_lossy(&response[..end]).to_lowercase();
+        let status = head[9..12].parse().unwrap();
+        let body = response.get(end + 4..).unwrap_or_default().to_vec();
+        (status, head, body)
+    }
+
+    fn start(builder: ExporterBuilder) -> (ExporterHandle, SocketAddr) {
+        let handle = builder.start().unwrap();
+        let addr = handle.local_addr().unwrap();
+        (handle, addr)
+    }
+
+    fn registry() -> Arc<Registry> {
+        let registry = Arc::new(Registry::new());
+        registry
+            .gauge_fn("queue_depth", "help", &[], || 3.0)
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn routes_ignore_query_string() {
+        let (_handle, addr) = start(
+            Exporter::builder(([127, 0, 0, 1], 0).into())
+                .registry(registry())
+                .self_metrics(false),
+        );
+
+        let (status, body) = get(addr, "/metrics?format=text&x=1", &[]);
+        assert_eq!(status, 200);
+        assert!(body.contains("queue_depth 3"), "{body}");
+        assert_eq!(get(addr, "/metrics.json?x=1", &[]).0, 200);
+        assert_eq!(get(addr, "/healthz?verbose", &[]).0, 200);
+        assert_eq!(get(addr, "/readyz?verbose", &[]).0, 200);
+        assert_eq!(get(addr, "/?x=1", &[]).0, 200);
+        assert_eq!(get(addr, "/unknown?x=1", &[]).0, 404);
+    }
+
+    #[test]
+    fn shutdown_and_drop_close_the_listener() {
+        let builder = || Exporter::builder(([127, 0, 0, 1], 0).into()).registry(registry());
+
+        let (handle, addr) = start(builder());
+        assert_ne!(addr.port(), 0);
+        assert_eq!(get(addr, "/metrics", &[]).0, 200);
+        handle.shutdown();
+        #[cfg(target_os = "linux")]
+        assert!(TcpStream::connect(addr).is_err());
+
+        let (handle, addr) = start(builder());
+        assert_eq!(get(addr, "/metrics", &[]).0, 200);
+        drop(handle);
+        #[cfg(target_os = "linux")]
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("metrics_lockfree_unix_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metrics.sock");
+
+        let handle = Exporter::builder_unix(&path)
+            .registry(registry())
+            .unix_permissions(0o660)
+            .start()
+            .unwrap();
+        assert!(handle.local_addr().is_none());
+        assert_eq!(handle.unix_addr().unwrap().as_pathname(), Some(&*path));
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        // only the socket is left in the directory
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let (status, body) = get_on(UnixStream::connect(&path).unwrap(), "/metrics", &[]);
+        assert_eq!(status, 200);
+        assert!(body.contains("queue_depth 3"), "{body}");
+
+        handle.shutdown();
+        assert!(UnixStream::connect(&path).is_err());
+        assert!(!path.exists());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn abstract_socket_round_trip() {
+        let name = format!("metrics_lockfree_{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+
+        let handle = Exporter::builder_unix_abstract(name.as_bytes())
+            .registry(registry())
+            .start()
+            .unwrap();
+        assert!(handle.local_addr().is_none());
+        assert_eq!(
+            handle.unix_addr().unwrap().as_abstract_name(),
+            Some(name.as_bytes())
+        );
+
+        let stream = UnixStream::connect_addr(&addr).unwrap();
+        assert_eq!(get_on(stream, "/metrics", &[]).0, 200);
+
+        // the server is leaked, the socket is closed
+        handle.shutdown();
+        assert!(UnixStream::connect_addr(&addr).is_err());
+    }
+
+    fn negotiate(accept: &str) -> &'static str {
+        Exporter::encoder_from_accept(&[accept]).content_type()
+    }
+
+    const PROTOBUF_ACCEPT: &str =
+        "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited";
+
+    #[test]
+    fn accept_q_values() {
+        let (protobuf, text) = (prometheus::PROTOBUF_FORMAT, prometheus::TEXT_FORMAT);
+        assert_eq!(negotiate(""), text);
+        assert_eq!(negotiate(PROTOBUF_ACCEPT), protobuf);
+        // the protobuf format is only served length delimited
+        assert_eq!(
+            negotiate("application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily"),
+            text
+        );
+
+        // what prometheus sends with protobuf scraping enabled
+        assert_eq!(
+            negotiate(&format!(
+                "{PROTOBUF_ACCEPT};q=0.7,text/plain;version=0.0.4;q=0.3,*/*;q=0.2"
+            )),
+            protobuf
+        );
+        assert_eq!(
+            negotiate(&format!("{PROTOBUF_ACCEPT};q=0.1, text/plain;q=0.9")),
+            text
+        );
+        assert_eq!(negotiate(&format!("{PROTOBUF_ACCEPT};q=0")), text);
+        assert_eq!(negotiate(&format!("{PROTOBUF_ACCEPT};Q=abc")), text);
+
+        // the order of the formats decides on a tie
+        assert_eq!(
+            negotiate(&format!("text/plain, {PROTOBUF_ACCEPT}")),
+            protobuf
+        );
+        assert_eq!(negotiate(&format!("*/*, {PROTOBUF_ACCEPT};q=0.5")), text);
+    }
+
+    #[test]
+    fn accept_json_and_openmetrics() {
+        let text = prometheus::TEXT_FORMAT;
+        assert_eq!(negotiate("application/json"), JSON_FORMAT);
+        assert_eq!(negotiate("application/json;q=0, text/plain"), text);
+        assert_eq!(negotiate("application/json;q=0.5, */*"), text);
+        assert_eq!(negotiate("text/*;q=0.1, application/json"), JSON_FORMAT);
+
+        // what prometheus sends by default, only openmetrics 1.0.0 is served
+        assert_eq!(
+            negotiate(
+                "application/openmetrics-text;version=1.0.0,application/openmetrics-text;\
+                 version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"
+            ),
+            OPENMETRICS_FORMAT
+        );
+        assert_eq!(
+            negotiate("application/openmetrics-text"),
+            OPENMETRICS_FORMAT
+        );
+        assert_eq!(
+            negotiate("application/openmetrics-text;version=0.0.1"),
+            text
+        );
+        assert_eq!(
+            negotiate("application/openmetrics-text;q=0.2, application/json;q=0.4"),
+            JSON_FORMAT
+        );
+    }
+
+    #[test]
+    fn protobuf_exposition() {
+        let (_handle, addr) = start(
+            Exporter::builder(([127, 0, 0, 1], 0).into())
+                .registry(registry())
+                .self_metrics(false),
+        );
+
+        let (status, head, body) = get_raw_on(
+            TcpStream::connect(addr).unwrap(),
+            "/metrics",
+            &[("Accept", PROTOBUF_ACCEPT)],
+        );
+        assert_eq!(status, 200);
+        assert!(
+            head.contains(&format!(
+                "content-type: {}",
+                prometheus::PROTOBUF_FORMAT.to_lowercase()
+            )),
+            "{head}"
+        );
+
+        // each family is preceded by its length as a varint
+        let mut is = protobuf::CodedInputStream::from_bytes(&body);
+        let length = is.read_raw_varint32().unwrap();
+        let family: prometheus::proto::MetricFamily =
+            protobuf::Message::parse_from_bytes(&is.read_raw_bytes(length).unwrap()).unwrap();
+        assert!(is.eof().unwrap());
+        assert_eq!(family.get_name(), "queue_depth");
+        assert_eq!(family.get_metric()[0].get_gauge().get_value(), 3.0);
+    }
+
+    #[test]
+    fn custom_endpoint_and_redirect() {
+        let (_handle, addr) = start(
+            Exporter::builder(([127, 0, 0, 1], 0).into())
+                .registry(registry())
+                .self_metrics(false)
+                .endpoint("/internal/metrics")
+                .not_found_policy(NotFoundPolicy::Redirect)
+                .worker_threads(3)
+                .thread_name("custom-http"),
+        );
+
+        let (status, body) = get(addr, "/internal/metrics", &[]);
+        assert_eq!(status, 200);
+        assert!(body.contains("queue_depth 3"), "{body}");
+        assert_eq!(get(addr, "/internal/metrics.json", &[]).0, 200);
+
+        let (status, head, _) = get_raw_on(TcpStream::connect(addr).unwrap(), "/metrics", &[]);
+        assert_eq!(status, 301);
+        assert!(head.contains("location: /internal/metrics"), "{head}");
+        assert!(get(addr, "/", &[]).1.contains("/internal/metrics"));
+    }
+
+    // name,value lines
+    struct CsvEncoder;
+
+    impl Encoder for CsvEncoder {
+        fn encode(&self, snapshot: &MetricsSnapshot, writer: &mut dyn Write) -> Result<()> {
+            for family in &snapshot.families {
+                for sample in &family.samples {
+                    writeln!(writer, "{},{}", family.name, sample.value)?;
+                }
+            }
+            Ok(())
+        }
+
+        fn content_type(&self) -> &str {
+            "text/csv"
+        }
+    }
+
+    #[test]
+    fn encoder_routes() {
+        let (_handle, addr) = start(
+            Exporter::builder(([127, 0, 0, 1], 0).into())
+                .registry(registry())
+                .self_metrics(false)
+                .encoder("/metrics.csv", CsvEncoder)
+                .encoder("/metrics.json", CsvEncoder),
+        );
+
+        let (status, head, body) =
+            get_raw_on(TcpStream::connect(addr).unwrap(), "/metrics.csv", &[]);
+        assert_eq!(status, 200);
+        assert!(head.contains("content-type: text/csv"), "{head}");
+        assert_eq!(body, b"queue_depth,3\n");
+
+        // user encoders come before the json route
+        assert_eq!(
+            get(addr, "/metrics.json", &[]),
+            (200, String::from("queue_depth,3\n"))
+        );
+        assert!(get(addr, "/", &[]).1.contains("/metrics.csv"));
+
+        // the endpoint is always negotiated
+        let err = Exporter::builder(([127, 0, 0, 1], 0).into())
+            .encoder("/metrics", CsvEncoder)
+            .start()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn control_routes_not_blocked_by_scrapes() {
+        // once armed, the scrape blocks until the test releases it. the gauge is also called
+        // when it is registered
+        let armed = Arc::new(AtomicBool::new(false));
+        let (entered, entered_rx) = mpsc::channel::<()>();
+        let (release, released) = mpsc::channel::<()>();
+        let (entered, released) = (Mutex::new(entered), Mutex::new(released));
+        let registry = Arc::new(Registry::new());
+        let blocking = armed.clone();
+        registry
+            .gauge_fn("slow", "help", &[], move || {
+                if blocking.load(Ordering::Relaxed) {
+                    let _ = entered.lock().unwrap().send(());
+                    let _ = released.lock().unwrap().recv();
+                }
+                1.0
+            })
+            .unwrap();
+
+        let (_handle, addr) = start(
+            Exporter::builder(([127, 0, 0, 1], 0).into())
+                .registry(registry)
+                .self_metrics(false)
+                .max_concurrent_scrapes(1),
+        );
+
+        armed.store(true, Ordering::Relaxed);
+        let scrape = thread::spawn(move || get(addr, "/metrics", &[]));
+        entered_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert_eq!(get(addr, "/metrics", &[]).0, 503);
+        assert_eq!(get(addr, "/healthz", &[]).0, 200);
+        assert_eq!(get(addr, "/readyz", &[]).0, 200);
+        assert_eq!(get(addr, "/unknown", &[]).0, 404);
+
+        release.send(()).unwrap();
+        let (status, body) = scrape.join().unwrap();
+        assert_eq!(status, 200);
+        assert!(body.contains("slow 1"), "{body}");
+    }
+
+    #[test]
+    fn self_metrics_per_exporter() {
+        let builder = || Exporter::builder(([127, 0, 0, 1], 0).into()).registry(registry());
+        let (_a, a) = start(builder());
+        let (_b, b) = start(builder());
+
+        // a response is counted once it is sent, the first scrape shows up in the next one
+        assert_eq!(get(a, "/metrics", &[]).0, 200);
+        thread::sleep(Duration::from_millis(100));
+        let (_, body) = get(a, "/metrics", &[]);
+        assert!(
+            body.contains("metrics_lockfree_scrape_requests_total{code=\"200\"}"),
+            "{body}"
+        );
+        let (_, body) = get(b, "/metrics", &[]);
+        assert!(
+            !body.contains("metrics_lockfree_scrape_requests_total{"),
+            "{body}"
+        );
+    }
+
+    #[test]
+    fn scrape_after_idle() {
+        let (_handle, addr) = start(
+            Exporter::builder(([127, 0, 0, 1], 0).into())
+                .registry(registry())
+                .self_metrics(false)
+                .write_timeout(Some(Duration::from_millis(100))),
+        );
+
+        // idle longer than the timeout, the listener keeps accepting connections
+        for _ in 0..2 {
+            thread::sleep(Duration::from_millis(300));
+            let stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            assert_eq!(get_on(stream, "/metrics", &[]).0, 200);
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_round_trip() {
+        use rustls::{Certificate, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+        // self signed for localhost, valid until 2126
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+        let (_handle, addr) = start(
+            Exporter::builder(([127, 0, 0, 1], 0).into())
+                .registry(registry())
+                .self_metrics(false)
+                .tls(dir.join("cert.pem"), dir.join("key.pem")),
+        );
+
+        let pem = std::fs::read(dir.join("cert.pem")).unwrap();
+        let mut roots = RootCertStore::empty();
+        for certificate in rustls_pemfile::certs(&mut pem.as_slice()).unwrap() {
+            roots.add(&Certificate(certificate)).unwrap();
+        }
+        let config = Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+
+        let connect = |host: &str| {
+            let connection =
+                ClientConnection::new(config.clone(), host.try_into().unwrap()).unwrap();
+            StreamOwned::new(connection, TcpStream::connect(addr).unwrap())
+        };
+
+        let (status, body) = get_on(connect("localhost"), "/metrics", &[]);
+        assert_eq!(status, 200);
+        assert!(body.contains("queue_depth 3"), "{body}");
+
+        // the certificate is verified against the name of the server
+        let mut stream = connect("example.com");
+        assert!(stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").is_err());
+
+        // plain http is not answered
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = vec![];
+        let _ = stream.read_to_end(&mut response);
+        assert!(!response.starts_with(b"HTTP/1.1 200"));
+    }
 }
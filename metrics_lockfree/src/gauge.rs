@@ -1,4 +1,4 @@
-use std::{cell::UnsafeCell, marker::PhantomPinned, pin::Pin};
+use std::{any::Any, cell::UnsafeCell, marker::PhantomPinned, pin::Pin, sync::Arc};
 
 // counter
 #[derive(Default)]
@@ -46,18 +46,31 @@ impl Default for GaugePin {
 
 pub struct Gauge {
     value: *mut u64,
+    // owner of the value, kept alive as long as the handle points to it
+    keep_alive: Option<Arc<dyn Any + Send + Sync>>,
 }
 
 impl Gauge {
     pub fn set(&mut self, inc: u64) {
         unsafe { *self.value = inc }
     }
+
+    pub fn keep_alive(mut self, owner: Arc<dyn Any + Send + Sync>) -> Self {
+        self.keep_alive = Some(owner);
+        self
+    }
 }
 
 impl From<&mut GaugePin> for Gauge {
     fn from(cell: &mut GaugePin) -> Self {
         Gauge {
             value: cell.as_mut_ptr(),
+            keep_alive: None,
         }
     }
 }
+
+// placeholder field of a derived struct for a gauge computed at scrape time, declared with
+// #[metric(compute = "path::to_fn")] where the function is `fn() -> f64`
+#[derive(Default)]
+pub struct GaugeFn;
@@ -0,0 +1,281 @@
+use crate::{
+    push::{self, PushHandle},
+    registry::Registry,
+    snapshot::MetricsSnapshot,
+};
+use std::{
+    io::{Error, ErrorKind, Result, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// how labels are written in the series path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphiteLabels {
+    // graphite 1.1 tagged series: requests;status=500
+    Tagged,
+    // label keys and values appended to the path: requests.status.500
+    Flattened,
+}
+
+pub struct GraphiteExporter;
+
+pub struct GraphiteBuilder {
+    target: SocketAddr,
+    registry: Arc<Registry>,
+    labels: GraphiteLabels,
+    prefix: Option<String>,
+    flush_interval: Duration,
+    connect_timeout: Duration,
+    write_timeout: Duration,
+    thread_name: String,
+}
+
+// connection to carbon, opened again after a failure
+struct Connection {
+    target: SocketAddr,
+    connect_timeout: Duration,
+    write_timeout: Duration,
+    stream: Option<TcpStream>,
+}
+
+impl GraphiteExporter {
+    // push the global registry to a carbon plaintext receiver, usually on port 2003
+    pub fn builder(target: SocketAddr) -> GraphiteBuilder {
+        GraphiteBuilder {
+            target,
+            registry: Registry::global(),
+            labels: GraphiteLabels::Tagged,
+            prefix: None,
+            flush_interval: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(5),
+            write_timeout: Duration::from_secs(5),
+            thread_name: String::from("metrics-graphite"),
+        }
+    }
+}
+
+impl GraphiteBuilder {
+    pub fn registry(mut self, registry: Arc<Registry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    pub fn labels(mut self, labels: GraphiteLabels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    // prepended to every metric path with a dot
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_owned());
+        self
+    }
+
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    pub fn thread_name(mut self, name: &str) -> Self {
+        self.thread_name = name.to_owned();
+        self
+    }
+
+    // carbon may be down when the exporter starts, the connection is only opened on flush
+    pub fn start(self) -> Result<PushHandle> {
+        let mut connection = Connection {
+            target: self.target,
+            connect_timeout: self.connect_timeout,
+            write_timeout: self.write_timeout,
+            stream: None,
+        };
+        let thread_name = self.thread_name.clone();
+        let flush_interval = self.flush_interval;
+
+        push::spawn(&thread_name, flush_interval, move |_| {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+
+            if let Ok(snapshot) = self.registry.snapshot() {
+                // the metrics of this interval are lost if carbon can't be reached
+                let _ = connection.send(self.lines(&snapshot, timestamp).as_bytes());
+            }
+        })
+    }
+
+    fn lines(&self, snapshot: &MetricsSnapshot, timestamp: u64) -> String {
+        let mut lines = String::new();
+        for family in &snapshot.families {
+            for sample in &family.samples {
+                if !sample.value.is_finite() {
+                    continue;
+                }
+
+                let mut path = match &self.prefix {
+                    Some(prefix) => format!(
+                        "{}.{}",
+                        sanitize(prefix, true),
+                        sanitize(&family.name, false)
+                    ),
+                    None => sanitize(&family.name, false),
+                };
+                sample.labels.iter().for_each(|(k, v)| match self.labels {
+                    GraphiteLabels::Tagged => {
+                        path.push_str(&format!(";{}={}", sanitize_tag(k), sanitize_tag(v)))
+                    }
+                    GraphiteLabels::Flattened => {
+                        path.push_str(&format!(".{}.{}", sanitize(k, false), sanitize(v, false)))
+                    }
+                });
+
+                lines.push_str(&format!("{path} {} {timestamp}\n", sample.value));
+            }
+        }
+        lines
+    }
+}
+
+impl Connection {
+    // a write on a connection carbon closed may only fail on the next one, a failed write is
+    // retried once on a new connection. the retry starts at the first line that wasn't fully
+    // written, carbon drops a partial line with its connection. lines are sent at most once:
+    // the ones written before the failure are not sent again, even though they may have been
+    // lost with the connection
+    fn send(&mut self, buffer: &[u8]) -> Result<()> {
+        match self.write(buffer) {
+            Ok(()) => Ok(()),
+            Err((written, _)) => self
+                .write(unsent_lines(buffer, written))
+                .map_err(|(_, e)| e),
+        }
+    }
+
+    // on failure, the number of bytes written before it
+    fn write(&mut self, buffer: &[u8]) -> std::result::Result<(), (usize, Error)> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => {
+                let stream = TcpStream::connect_timeout(&self.target, self.connect_timeout)
+                    .and_then(|stream| {
+                        stream.set_write_timeout(Some(self.write_timeout))?;
+                        Ok(stream)
+                    })
+                    .map_err(|e| (0, e))?;
+                self.stream.insert(stream)
+            }
+        };
+
+        let mut written = 0;
+        while written < buffer.len() {
+            match stream.write(&buffer[written..]) {
+                Ok(0) => {
+                    self.stream = None;
+                    return Err((written, Error::from(ErrorKind::WriteZero)));
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.stream = None;
+                    return Err((written, e));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// lines of the buffer not fully written in the first `written` bytes
+fn unsent_lines(buffer: &[u8], written: usize) -> &[u8] {
+    let start = buffer[..written]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    &buffer[start..]
+}
+
+// spaces separate the fields of a line, dots separate the segments of a path. they are kept in
+// the prefix only
+fn sanitize(s: &str, keep_dots: bool) -> String {
+    s.chars()
+        .map(|c| match c {
+            ';' => '_',
+            '.' if !keep_dots => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+// tag names and values can't contain ';', and '=' would be read as the separator
+fn sanitize_tag(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            ';' | '=' | '~' | '!' | '^' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn retry_from_first_unsent_line() {
+        let buffer = b"a 1 10\nb 2 10\nc 3 10\n";
+        assert_eq!(unsent_lines(buffer, 0), buffer);
+        assert_eq!(unsent_lines(buffer, 3), buffer);
+        assert_eq!(unsent_lines(buffer, 7), b"b 2 10\nc 3 10\n");
+        assert_eq!(unsent_lines(buffer, 10), b"b 2 10\nc 3 10\n");
+        assert_eq!(unsent_lines(buffer, 14), b"c 3 10\n");
+        assert_eq!(unsent_lines(buffer, buffer.len()), b"");
+    }
+
+    #[test]
+    fn plaintext_lines() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let registry = Arc::new(Registry::new());
+        registry
+            .gauge_fn(
+                "queue.depth",
+                "help",
+                &[("pool".to_owned(), "a b".to_owned())],
+                || 3.0,
+            )
+            .unwrap();
+
+        let handle = GraphiteExporter::builder(listener.local_addr().unwrap())
+            .registry(registry)
+            .prefix("app.api")
+            .flush_interval(Duration::from_secs(3600))
+            .start()
+            .unwrap();
+        // flushed when the handle is dropped
+        drop(handle);
+
+        let (stream, _) = listener.accept().unwrap();
+        let line = BufReader::new(stream).lines().next().unwrap().unwrap();
+        let (path, timestamp) = line.rsplit_once(' ').unwrap();
+        assert_eq!(path, "app.api.queue_depth;pool=a_b 3");
+        assert!(timestamp.parse::<u64>().unwrap() > 0);
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+// enough of an http/1.1 client to post metrics to a collector, plain http only
+
+// parsed http://host[:port]/path?query url
+#[derive(Debug, Clone)]
+pub(crate) struct Url {
+    host: String,
+    port: u16,
+    // path and query
+    path: String,
+}
+
+pub(crate) struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+// outcome of a request
+#[cfg(any(feature = "otlp", feature = "remote-write"))]
+pub(crate) enum Sent {
+    Done,
+    // the receiver is down or overloaded, the request may succeed later
+    Retry,
+    // the receiver rejected the request, it would be rejected again
+    Rejected,
+}
+
+// retries of a failed request, the wait before a retry starts at min and doubles up to max
+#[cfg(any(feature = "otlp", feature = "remote-write"))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Backoff {
+    pub max_retries: u32,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Url {
+    pub(crate) fn parse(url: &str) -> Result<Self> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, format!("{msg}: {url}"));
+
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid("Only http:// urls are supported"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        // [::1]:8086 or host:8086
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => (
+                &authority[..i],
+                authority[i + 1..]
+                    .parse()
+                    .map_err(|_| invalid("Invalid port in url"))?,
+            ),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid("Missing host in url"));
+        }
+
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+// one request per connection, the collectors we talk to are called every few seconds at most
+pub(crate) fn post(
+    url: &Url,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+) -> Result<Response> {
+    let host = url.host.trim_start_matches('[').trim_end_matches(']');
+    let addr = (host, url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Can't resolve {}", url.host)))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host,
+        url.port,
+        body.len()
+    );
+    if !headers
+        .iter()
+        .any(|(k, _)| k.eq_ignore_ascii_case("User-Agent"))
+    {
+        request.push_str(concat!(
+            "User-Agent: metrics_lockfree/",
+            env!("CARGO_PKG_VERSION"),
+            "\r\n"
+        ));
+    }
+    headers
+        .iter()
+        .for_each(|(k, v)| request.push_str(&format!("{k}: {v}\r\n")));
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    read_response(stream)
+}
+
+// call `send` again while it asks for a retry, at most `max_retries` times
+#[cfg(any(feature = "otlp", feature = "remote-write"))]
+pub(crate) fn with_retries<F>(backoff: &Backoff, mut send: F) -> Sent
+where
+    F: FnMut() -> Sent,
+{
+    let mut wait = backoff.min;
+    let mut retries = 0;
+    loop {
+        match send() {
+            Sent::Retry if retries < backoff.max_retries => {
+                std::thread::sleep(wait);
+                wait = (wait * 2).min(backoff.max);
+                retries += 1;
+            }
+            sent => return sent,
+        }
+    }
+}
+
+fn read_response(stream: TcpStream) -> Result<Response> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid http response");
+    let mut reader = BufReader::new(stream);
+
+    // HTTP/1.1 204 No Content
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+
+    let mut content_length = None;
+    let mut chunked = false;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = header.split_once(':') {
+            if k.eq_ignore_ascii_case("content-length") {
+                content_length = v.trim().parse::<usize>().ok();
+            } else if k.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = v.trim().eq_ignore_ascii_case("chunked");
+            }
+        }
+    }
+
+    let mut body = vec![];
+    if chunked {
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = usize::from_str_radix(line.trim().split(';').next().unwrap_or(""), 16)
+                .map_err(|_| invalid())?;
+            if size == 0 {
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            // trailing crlf of the chunk
+            line.clear();
+            reader.read_line(&mut line)?;
+        }
+    } else if let Some(length) = content_length {
+        body.resize(length, 0);
+        reader.read_exact(&mut body)?;
+    } else {
+        reader.read_to_end(&mut body)?;
+    }
+
+    Ok(Response { status, body })
+}
+
+// receiver of the push exporter tests
+#[cfg(all(test, any(feature = "otlp", feature = "remote-write")))]
+pub(crate) mod mock {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc::{self, Receiver},
+        thread,
+    };
+
+    pub(crate) struct Request {
+        // lowercase names
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl Request {
+        pub(crate) fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    // url of a receiver answering with `statuses` in turn, then 200. the requests are sent to
+    // the returned channel
+    pub(crate) fn receiver(statuses: Vec<u16>) -> (String, Receiver<Request>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let url = format!("http://{}/write", listener.local_addr().unwrap());
+        let mut statuses = statuses.into_iter();
+        let (sender, requests) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = vec![];
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 && line.trim_end() != "" {
+                    if let Some((k, v)) = line.split_once(':') {
+                        headers.push((k.trim().to_lowercase(), v.trim().to_owned()));
+                    }
+                    line.clear();
+                }
+                let length = headers
+                    .iter()
+                    .find(|(k, _)| k == "content-length")
+                    .and_then(|(_, v)| v.parse().ok())
+                    .unwrap_or(0);
+                let mut body = vec![0; length];
+                let _ = reader.read_exact(&mut body);
+
+                let status = statuses.next().unwrap_or(200);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+                let _ = sender.send(Request { headers, body });
+            }
+        });
+
+        (url, requests)
+    }
+}
@@ -0,0 +1,385 @@
+use crate::{
+    encoder::Encoder,
+    http_client::{self, Url},
+    push::{self, PushHandle},
+    registry::Registry,
+    snapshot::MetricsSnapshot,
+};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    fs::OpenOptions,
+    io::{Error, Result, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const INFLUX_FORMAT: &str = "text/plain; charset=utf-8";
+
+type LineKey = (String, Vec<(String, String)>);
+
+// influxdb line protocol. the fields of a derived struct are the fields of a measurement named
+// after it, one line per label set:
+//   MyMetrics c=1,g=0 1700000000000000000
+//   MyMetrics,key_a=val_b ct=2 1700000000000000000
+// other metrics are measurements of their own with a single "value" field. values are always
+// written as floats so that a field never changes type, without the exponent notation influxdb
+// doesn't parse
+#[derive(Debug, Default)]
+pub struct InfluxEncoder;
+
+impl Encoder for InfluxEncoder {
+    fn encode(&self, snapshot: &MetricsSnapshot, writer: &mut dyn Write) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+
+        // fields of each (measurement, sorted tags), in the order they are first seen
+        let mut lines: Vec<(LineKey, Vec<(String, f64)>)> = vec![];
+
+        for family in &snapshot.families {
+            let (measurement, field) = match &family.source {
+                Some(source) => (source.as_str(), family.name.as_str()),
+                None => (family.name.as_str(), "value"),
+            };
+
+            for sample in family.samples.iter().filter(|s| s.value.is_finite()) {
+                let mut tags: Vec<(String, String)> = sample
+                    .labels
+                    .iter()
+                    .filter(|(_, v)| !v.is_empty())
+                    .cloned()
+                    .collect();
+                tags.sort_unstable();
+
+                let key = (measurement.to_owned(), tags);
+                match lines.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, fields)) => fields.push((field.to_owned(), sample.value)),
+                    None => lines.push((key, vec![(field.to_owned(), sample.value)])),
+                }
+            }
+        }
+
+        let mut buffer = String::new();
+        for ((measurement, tags), fields) in lines {
+            buffer.push_str(&escape(&measurement, &[',', ' ']));
+            tags.iter().for_each(|(k, v)| {
+                buffer.push_str(&format!(
+                    ",{}={}",
+                    escape(k, &[',', '=', ' ']),
+                    escape(v, &[',', '=', ' '])
+                ));
+            });
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(k, v)| format!("{}={v}", escape(k, &[',', '=', ' '])))
+                .collect();
+            buffer.push_str(&format!(" {} {timestamp}\n", fields.join(",")));
+        }
+
+        writer.write_all(buffer.as_bytes())
+    }
+
+    fn content_type(&self) -> &str {
+        INFLUX_FORMAT
+    }
+}
+
+fn escape(s: &str, chars: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            // a line can't span several lines, line breaks are written as escape sequences
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            // otherwise a backslash before an escaped character would be read as the escape
+            '\\' => escaped.push_str("\\\\"),
+            c if chars.contains(&c) => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub struct InfluxExporter;
+
+// where the lines are pushed
+enum Target {
+    // influxdb write api, with an optional token
+    Http {
+        url: Url,
+        token: Option<String>,
+    },
+    // appended to a file
+    File(PathBuf),
+    // telegraf socket_listener on a unix stream socket
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Writer(Box<dyn Write + Send>),
+}
+
+pub struct InfluxBuilder {
+    target: Target,
+    registry: Arc<Registry>,
+    flush_interval: Duration,
+    timeout: Duration,
+    thread_name: String,
+}
+
+impl InfluxExporter {
+    // post to an influxdb write endpoint, such as
+    // http://localhost:8086/api/v2/write?org=o&bucket=b&precision=ns
+    pub fn http(url: &str) -> Result<InfluxBuilder> {
+        Ok(Self::builder(Target::Http {
+            url: Url::parse(url)?,
+            token: None,
+        }))
+    }
+
+    // append to a file, created if needed
+    pub fn file<P: AsRef<Path>>(path: P) -> InfluxBuilder {
+        Self::builder(Target::File(path.as_ref().to_owned()))
+    }
+
+    // write to a unix stream socket, connected again after a failure
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<Path>>(path: P) -> InfluxBuilder {
+        Self::builder(Target::Unix(path.as_ref().to_owned()))
+    }
+
+    pub fn writer<W: Write + Send + 'static>(writer: W) -> InfluxBuilder {
+        Self::builder(Target::Writer(Box::new(writer)))
+    }
+
+    fn builder(target: Target) -> InfluxBuilder {
+        InfluxBuilder {
+            target,
+            registry: Registry::global(),
+            flush_interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(10),
+            thread_name: String::from("metrics-influx"),
+        }
+    }
+}
+
+impl InfluxBuilder {
+    pub fn registry(mut self, registry: Arc<Registry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    // sent as "Authorization: Token <token>" to the http endpoint, ignored by other targets
+    pub fn token(mut self, token: &str) -> Self {
+        if let Target::Http { token: t, .. } = &mut self.target {
+            *t = Some(token.to_owned());
+        }
+        self
+    }
+
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    // connect, write and read timeout of http requests
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn thread_name(mut self, name: &str) -> Self {
+        self.thread_name = name.to_owned();
+        self
+    }
+
+    pub fn start(self) -> Result<PushHandle> {
+        let mut target = self.target;
+        let registry = self.registry;
+        let timeout = self.timeout;
+        #[cfg(unix)]
+        let mut unix_stream: Option<UnixStream> = None;
+
+        push::spawn(&self.thread_name, self.flush_interval, move |_| {
+            let mut buffer = vec![];
+            let encoded = registry
+                .snapshot()
+                .and_then(|snapshot| InfluxEncoder.encode(&snapshot, &mut buffer));
+            if encoded.is_err() || buffer.is_empty() {
+                return;
+            }
+
+            // the lines of this interval are lost if the target can't be written to
+            let _ = match &mut target {
+                Target::Http { url, token } => {
+                    let authorization = token.as_ref().map(|t| format!("Token {t}"));
+                    let mut headers = vec![("Content-Type", INFLUX_FORMAT)];
+                    if let Some(authorization) = &authorization {
+                        headers.push(("Authorization", authorization));
+                    }
+                    http_client::post(url, &headers, &buffer, timeout).and_then(|response| {
+                        match response.status {
+                            200..=299 => Ok(()),
+                            status => Err(Error::other(format!(
+                                "Influx write failed with status {status}: {}",
+                                String::from_utf8_lossy(&response.body).trim()
+                            ))),
+                        }
+                    })
+                }
+                Target::File(path) => OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| file.write_all(&buffer)),
+                #[cfg(unix)]
+                Target::Unix(path) => {
+                    let res = match &mut unix_stream {
+                        Some(stream) => stream.write_all(&buffer),
+                        None => UnixStream::connect(&*path).and_then(|mut stream| {
+                            stream.write_all(&buffer)?;
+                            unix_stream = Some(stream);
+                            Ok(())
+                        }),
+                    };
+                    if res.is_err() {
+                        unix_stream = None;
+                    }
+                    res
+                }
+                Target::Writer(writer) => writer.write_all(&buffer).and_then(|_| writer.flush()),
+            };
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{snapshot::family, types::MetricType};
+
+    #[test]
+    fn float_fields() {
+        let gauge = |name: &str, value: f64| {
+            family(name, MetricType::Gauge, &[(&[("pool", "a b")], value)])
+        };
+        let snapshot = MetricsSnapshot {
+            families: vec![
+                gauge("small", 1e-7),
+                gauge("large", 1e21),
+                gauge("whole", 3.0),
+                gauge("nan", f64::NAN),
+            ],
+        };
+
+        let mut buffer = vec![];
+        InfluxEncoder.encode(&snapshot, &mut buffer).unwrap();
+        let fields: Vec<&str> = std::str::from_utf8(&buffer)
+            .unwrap()
+            .lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "small,pool=a\\ b value=0.0000001",
+                "large,pool=a\\ b value=1000000000000000000000",
+                "whole,pool=a\\ b value=3",
+            ]
+        );
+    }
+
+    #[test]
+    fn escaped_tags() {
+        let snapshot = MetricsSnapshot {
+            families: vec![family(
+                "jobs, total",
+                MetricType::Counter,
+                &[(&[("path", "C:\\tmp\\"), ("error", "a=b\nc\r")], 1.0)],
+            )],
+        };
+
+        let mut buffer = vec![];
+        InfluxEncoder.encode(&snapshot, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+        assert_eq!(text.lines().count(), 1);
+        assert_eq!(
+            text.rsplit_once(' ').unwrap().0,
+            r"jobs\,\ total,error=a\=b\nc\r,path=C:\\tmp\\ value=1"
+        );
+    }
+
+    fn registry() -> Arc<Registry> {
+        let registry = Arc::new(Registry::new());
+        registry
+            .gauge_fn("queue_depth", "help", &[], || 3.0)
+            .unwrap();
+        registry
+    }
+
+    fn lines(text: &str) -> Vec<&str> {
+        text.lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn file_target() {
+        let path =
+            std::env::temp_dir().join(format!("metrics_lockfree_influx_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // flushed when the handle is dropped, appended by the next exporter
+        for _ in 0..2 {
+            let handle = InfluxExporter::file(&path)
+                .registry(registry())
+                .flush_interval(Duration::from_secs(3600))
+                .start()
+                .unwrap();
+            drop(handle);
+        }
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(lines(&text), ["queue_depth value=3", "queue_depth value=3"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_target() {
+        use std::{io::Read, os::unix::net::UnixListener};
+
+        let path = std::env::temp_dir().join(format!(
+            "metrics_lockfree_influx_{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let handle = InfluxExporter::unix(&path)
+            .registry(registry())
+            .flush_interval(Duration::from_millis(50))
+            .start()
+            .unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        // the connection is kept between flushes and closed with the exporter
+        std::thread::sleep(Duration::from_millis(120));
+        drop(handle);
+
+        let mut text = String::new();
+        stream.read_to_string(&mut text).unwrap();
+        let lines = lines(&text);
+        assert!(lines.len() >= 2, "{text}");
+        assert!(
+            lines.iter().all(|line| *line == "queue_depth value=3"),
+            "{text}"
+        );
+        assert!(listener.set_nonblocking(true).is_ok());
+        assert!(listener.accept().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
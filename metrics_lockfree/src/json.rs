@@ -0,0 +1,145 @@
+use crate::{encoder::Encoder, snapshot::MetricsSnapshot, types::MetricType};
+use std::io::{Result, Write};
+
+pub const JSON_FORMAT: &str = "application/json";
+
+// encode the snapshot as a flat json array, one object per sample:
+// [{"name":"c","type":"counter","help":"","labels":{"k":"v"},"value":1}]
+#[derive(Debug, Default)]
+pub struct JsonEncoder;
+
+impl Encoder for JsonEncoder {
+    fn encode(&self, snapshot: &MetricsSnapshot, writer: &mut dyn Write) -> Result<()> {
+        let mut buffer = String::from("[");
+        let mut first = true;
+
+        for family in &snapshot.families {
+            for sample in &family.samples {
+                if !first {
+                    buffer.push(',');
+                }
+                first = false;
+
+                buffer.push_str("{\"name\":");
+                push_json_string(&mut buffer, &family.name);
+                buffer.push_str(",\"type\":");
+                push_json_string(
+                    &mut buffer,
+                    match family.metric_type {
+                        MetricType::Counter => "counter",
+                        MetricType::Gauge => "gauge",
+                    },
+                );
+                buffer.push_str(",\"help\":");
+                push_json_string(&mut buffer, &family.help);
+
+                buffer.push_str(",\"labels\":{");
+                for (i, (k, v)) in sample.labels.iter().enumerate() {
+                    if i > 0 {
+                        buffer.push(',');
+                    }
+                    push_json_string(&mut buffer, k);
+                    buffer.push(':');
+                    push_json_string(&mut buffer, v);
+                }
+                buffer.push('}');
+
+                buffer.push_str(",\"value\":");
+                push_json_value(&mut buffer, sample.value);
+                buffer.push('}');
+            }
+        }
+
+        buffer.push_str("]\n");
+        writer.write_all(buffer.as_bytes())
+    }
+
+    fn content_type(&self) -> &str {
+        JSON_FORMAT
+    }
+}
+
+// json has no representation for NaN and infinities, use the same strings as prometheus
+fn push_json_value(buffer: &mut String, value: f64) {
+    if value.is_nan() {
+        buffer.push_str("\"NaN\"");
+    } else if value.is_infinite() {
+        buffer.push_str(if value > 0.0 { "\"+Inf\"" } else { "\"-Inf\"" });
+    } else {
+        buffer.push_str(&value.to_string());
+    }
+}
+
+fn push_json_string(buffer: &mut String, s: &str) {
+    buffer.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => buffer.push_str("\\\""),
+            '\\' => buffer.push_str("\\\\"),
+            '\n' => buffer.push_str("\\n"),
+            '\r' => buffer.push_str("\\r"),
+            '\t' => buffer.push_str("\\t"),
+            c if (c as u32) < 0x20 => buffer.push_str(&format!("\\u{:04x}", c as u32)),
+            c => buffer.push(c),
+        }
+    }
+    buffer.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::{family, FamilySnapshot};
+
+    #[test]
+    fn json_samples() {
+        let snapshot = MetricsSnapshot {
+            families: vec![
+                FamilySnapshot {
+                    help: "quote \" and\nline".to_owned(),
+                    ..family(
+                        "requests",
+                        MetricType::Counter,
+                        &[(&[], 1.0), (&[("path", "/a\\b"), ("code", "200")], 2.5)],
+                    )
+                },
+                family(
+                    "ratio",
+                    MetricType::Gauge,
+                    &[
+                        (&[("k", "nan")], f64::NAN),
+                        (&[("k", "inf")], f64::INFINITY),
+                        (&[("k", "-inf\u{1}")], f64::NEG_INFINITY),
+                    ],
+                ),
+                family("empty", MetricType::Gauge, &[]),
+            ],
+        };
+
+        let mut buffer = vec![];
+        JsonEncoder.encode(&snapshot, &mut buffer).unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            concat!(
+                r#"[{"name":"requests","type":"counter","help":"quote \" and\nline","labels":{},"#,
+                r#""value":1},"#,
+                r#"{"name":"requests","type":"counter","help":"quote \" and\nline","#,
+                r#""labels":{"path":"/a\\b","code":"200"},"value":2.5},"#,
+                r#"{"name":"ratio","type":"gauge","help":"","labels":{"k":"nan"},"value":"NaN"},"#,
+                r#"{"name":"ratio","type":"gauge","help":"","labels":{"k":"inf"},"value":"+Inf"},"#,
+                r#"{"name":"ratio","type":"gauge","help":"","labels":{"k":"-inf\u0001"},"#,
+                r#""value":"-Inf"}]"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn empty_snapshot() {
+        let mut buffer = vec![];
+        JsonEncoder
+            .encode(&MetricsSnapshot::default(), &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, b"[]\n");
+    }
+}
@@ -1,6 +1,32 @@
+pub mod auth;
+#[cfg(feature = "compression")]
+mod compression;
 pub mod counter;
+pub mod delta;
+pub mod encoder;
 pub mod exporter;
 pub mod gauge;
+pub mod graphite;
+mod http_client;
+pub mod influx;
+pub mod json;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+#[cfg(all(feature = "process", target_os = "linux"))]
+pub mod process;
 pub mod prometheus;
+#[cfg(any(feature = "otlp", feature = "remote-write"))]
+mod proto;
+pub mod push;
+pub mod registry;
+#[cfg(feature = "remote-write")]
+pub mod remote_write;
+mod self_metrics;
+pub mod snapshot;
+pub mod statsd;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod types;
-pub use exporter::Exporter;
+pub use auth::Secret;
+pub use exporter::{Exporter, ExporterBuilder, ExporterHandle, NotFoundPolicy};
+pub use registry::{RegistrationHandle, Registry};
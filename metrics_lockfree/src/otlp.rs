@@ -0,0 +1,378 @@
+use crate::{
+    http_client::{self, Backoff, Sent, Url},
+    proto::message,
+    push::{self, PushHandle},
+    registry::Registry,
+    snapshot::{FamilySnapshot, MetricsSnapshot},
+    types::MetricType,
+};
+use protobuf::ProtobufResult;
+use std::{
+    io::{Error, Result},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const OTLP_FORMAT: &str = "application/x-protobuf";
+
+// scope of the metrics that are not fields of a derived struct
+const DEFAULT_SCOPE: &str = "metrics_lockfree";
+
+// opentelemetry.proto.metrics.v1.AggregationTemporality
+const AGGREGATION_TEMPORALITY_CUMULATIVE: i32 = 2;
+
+pub struct OtlpExporter;
+
+pub struct OtlpBuilder {
+    url: Url,
+    registry: Arc<Registry>,
+    resource: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    flush_interval: Duration,
+    timeout: Duration,
+    backoff: Backoff,
+    thread_name: String,
+}
+
+impl OtlpExporter {
+    // push the global registry to an otlp/http receiver, such as the collector's
+    // http://localhost:4318/v1/metrics
+    pub fn builder(url: &str) -> Result<OtlpBuilder> {
+        Ok(OtlpBuilder {
+            url: Url::parse(url)?,
+            registry: Registry::global(),
+            resource: vec![],
+            headers: vec![],
+            flush_interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(10),
+            backoff: Backoff {
+                max_retries: 3,
+                min: Duration::from_millis(100),
+                max: Duration::from_secs(5),
+            },
+            thread_name: String::from("metrics-otlp"),
+        })
+    }
+}
+
+impl OtlpBuilder {
+    pub fn registry(mut self, registry: Arc<Registry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    // string attribute of the resource the metrics are sent for, such as service.name. the
+    // service.name is "unknown_service" unless set
+    pub fn resource_attribute(mut self, key: &str, value: &str) -> Self {
+        self.resource.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    // extra header of every request, such as an api key asked by the receiver
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    // connect, write and read timeout of http requests
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // retries of a failed request, the metrics of the interval are dropped after the last one
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.backoff.max_retries = max_retries;
+        self
+    }
+
+    // the wait before a retry starts at min and doubles up to max
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.backoff.min = min;
+        self.backoff.max = max.max(min);
+        self
+    }
+
+    pub fn thread_name(mut self, name: &str) -> Self {
+        self.thread_name = name.to_owned();
+        self
+    }
+
+    pub fn start(mut self) -> Result<PushHandle> {
+        if !self.resource.iter().any(|(k, _)| k == "service.name") {
+            self.resource
+                .push(("service.name".to_owned(), "unknown_service".to_owned()));
+        }
+        // the counters are cumulative from the creation of the registry, except the ones of
+        // plain registrations, which may have counted before
+        let start_time = self
+            .registry
+            .created()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let thread_name = self.thread_name.clone();
+        let flush_interval = self.flush_interval;
+
+        push::spawn(&thread_name, flush_interval, move |last| {
+            let body = self.registry.snapshot().and_then(|snapshot| {
+                request(&snapshot, &self.resource, start_time, unix_nanos())
+                    .map_err(|e| Error::other(format!("Protobuf error: {e}")))
+            });
+            let Ok(body) = body else {
+                return;
+            };
+
+            // the metrics of this interval are lost if the receiver can't be reached. the last
+            // flush isn't retried, the drop of the handle waits for it
+            if last {
+                self.send(&body);
+            } else {
+                http_client::with_retries(&self.backoff, || self.send(&body));
+            }
+        })
+    }
+
+    fn send(&self, body: &[u8]) -> Sent {
+        let mut headers = vec![("Content-Type", OTLP_FORMAT)];
+        headers.extend(self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+
+        // the statuses the otlp/http spec asks to retry
+        match http_client::post(&self.url, &headers, body, self.timeout) {
+            Ok(response) => match response.status {
+                200..=299 => Sent::Done,
+                429 | 502..=504 => Sent::Retry,
+                _ => Sent::Rejected,
+            },
+            Err(_) => Sent::Retry,
+        }
+    }
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+// ExportMetricsServiceRequest with a single resource, and a scope per derived struct:
+//   resource_metrics { resource { attributes }, scope_metrics { scope { name }, metrics } }
+// counters are cumulative monotonic sums and gauges are gauges. there are no histograms in this
+// crate
+fn request(
+    snapshot: &MetricsSnapshot,
+    resource: &[(String, String)],
+    start_time: u64,
+    time: u64,
+) -> ProtobufResult<Vec<u8>> {
+    // families of each scope, in the order they are first seen
+    let mut scopes: Vec<(&str, Vec<&FamilySnapshot>)> = vec![];
+    for family in &snapshot.families {
+        let scope = family.source.as_deref().unwrap_or(DEFAULT_SCOPE);
+        match scopes.iter_mut().find(|(s, _)| *s == scope) {
+            Some((_, families)) => families.push(family),
+            None => scopes.push((scope, vec![family])),
+        }
+    }
+
+    let resource_metrics = message(|os| {
+        os.write_bytes(
+            1,
+            &message(|os| {
+                resource
+                    .iter()
+                    .try_for_each(|(k, v)| os.write_bytes(1, &key_value(k, v)?))
+            })?,
+        )?;
+        scopes.iter().try_for_each(|(scope, families)| {
+            os.write_bytes(
+                2,
+                &message(|os| {
+                    os.write_bytes(1, &message(|os| os.write_string(1, scope))?)?;
+                    families.iter().try_for_each(|family| {
+                        os.write_bytes(2, &metric(family, start_time, time)?)
+                    })
+                })?,
+            )
+        })
+    })?;
+
+    message(|os| os.write_bytes(1, &resource_metrics))
+}
+
+// Metric { name, description, gauge = 5 | sum = 7 }
+fn metric(family: &FamilySnapshot, start_time: u64, time: u64) -> ProtobufResult<Vec<u8>> {
+    let points = family
+        .samples
+        .iter()
+        .map(|sample| {
+            // NumberDataPoint { start_time_unix_nano, time_unix_nano, as_double, attributes }
+            message(|os| {
+                if family.metric_type == MetricType::Counter {
+                    os.write_fixed64(2, start_time)?;
+                }
+                os.write_fixed64(3, time)?;
+                os.write_double(4, sample.value)?;
+                sample
+                    .labels
+                    .iter()
+                    .try_for_each(|(k, v)| os.write_bytes(7, &key_value(k, v)?))
+            })
+        })
+        .collect::<ProtobufResult<Vec<_>>>()?;
+
+    message(|os| {
+        os.write_string(1, &family.name)?;
+        os.write_string(2, &family.help)?;
+        match family.metric_type {
+            // Sum { data_points, aggregation_temporality, is_monotonic }
+            MetricType::Counter => os.write_bytes(
+                7,
+                &message(|os| {
+                    points.iter().try_for_each(|p| os.write_bytes(1, p))?;
+                    os.write_enum(2, AGGREGATION_TEMPORALITY_CUMULATIVE)?;
+                    os.write_bool(3, true)
+                })?,
+            ),
+            // Gauge { data_points }
+            MetricType::Gauge => os.write_bytes(
+                5,
+                &message(|os| points.iter().try_for_each(|p| os.write_bytes(1, p)))?,
+            ),
+        }
+    })
+}
+
+// KeyValue { key, value: AnyValue { string_value } }
+fn key_value(key: &str, value: &str) -> ProtobufResult<Vec<u8>> {
+    message(|os| {
+        os.write_string(1, key)?;
+        os.write_bytes(2, &message(|os| os.write_string(1, value))?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http_client::mock,
+        proto::{fields, Field},
+        snapshot::family,
+    };
+
+    // nested message of the only `number` field
+    fn field(buffer: &[u8], number: u32) -> Vec<(u32, Field)> {
+        let found: Vec<Field> = fields(buffer)
+            .into_iter()
+            .filter(|(n, _)| *n == number)
+            .map(|(_, f)| f)
+            .collect();
+        assert_eq!(found.len(), 1, "field {number}");
+        fields(found[0].bytes())
+    }
+
+    #[test]
+    fn protobuf_request() {
+        let registry = Arc::new(Registry::new());
+        registry
+            .register(|| {
+                vec![FamilySnapshot {
+                    help: "handled requests".to_owned(),
+                    ..family(
+                        "requests",
+                        MetricType::Counter,
+                        &[(&[("status", "500")], 5.0)],
+                    )
+                }
+                .to_metric_family()]
+            })
+            .unwrap();
+        registry
+            .gauge_fn("queue_depth", "help", &[], || 2.0)
+            .unwrap();
+
+        // the first request is answered 503 and retried
+        let (url, requests) = mock::receiver(vec![503]);
+        let handle = OtlpExporter::builder(&url)
+            .unwrap()
+            .registry(registry.clone())
+            .resource_attribute("service.name", "api")
+            .header("X-Api-Key", "secret")
+            .flush_interval(Duration::from_millis(10))
+            .backoff(Duration::from_millis(1), Duration::from_millis(1))
+            .start()
+            .unwrap();
+        let first = requests.recv().unwrap();
+        let retried = requests.recv().unwrap();
+        drop(handle);
+
+        assert_eq!(first.header("content-type"), Some(OTLP_FORMAT));
+        assert_eq!(first.header("x-api-key"), Some("secret"));
+        assert_eq!(first.body, retried.body);
+
+        // ExportMetricsServiceRequest { resource_metrics = 1 }
+        let resource_metrics = field(&first.body, 1);
+        // ResourceMetrics { resource { attributes }, scope_metrics }
+        assert_eq!(resource_metrics[0].0, 1);
+        let resource = fields(resource_metrics[0].1.bytes());
+        let attribute = fields(resource[0].1.bytes());
+        assert_eq!(attribute[0], (1, Field::Bytes(b"service.name".to_vec())));
+        assert_eq!(fields(attribute[1].1.bytes())[0].1.string(), "api");
+
+        assert_eq!(resource_metrics[1].0, 2);
+        let scope_metrics = fields(resource_metrics[1].1.bytes());
+        // ScopeMetrics { scope { name }, metrics }
+        assert_eq!(scope_metrics[0].0, 1);
+        assert_eq!(
+            fields(scope_metrics[0].1.bytes())[0].1.string(),
+            DEFAULT_SCOPE
+        );
+        let metrics: Vec<Vec<(u32, Field)>> = scope_metrics[1..]
+            .iter()
+            .map(|(n, metric)| {
+                assert_eq!(*n, 2);
+                fields(metric.bytes())
+            })
+            .collect();
+        let metric = |name: &str| {
+            metrics
+                .iter()
+                .find(|m| m[0].1.string() == name)
+                .unwrap()
+                .clone()
+        };
+
+        // Metric { name, description, sum { data_points, temporality, monotonic } }
+        let requests = metric("requests");
+        assert_eq!(requests[1].1.string(), "handled requests");
+        assert_eq!(requests[2].0, 7);
+        let sum = fields(requests[2].1.bytes());
+        assert_eq!(sum[1], (2, Field::Varint(2)));
+        assert_eq!(sum[2], (3, Field::Varint(1)));
+        let point = fields(sum[0].1.bytes());
+        let start_time = registry
+            .created()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        assert_eq!(point[0], (2, Field::Fixed64(start_time)));
+        assert_eq!(point[1].0, 3);
+        assert_eq!(point[2], (4, Field::Fixed64(5.0f64.to_bits())));
+        let attribute = fields(point[3].1.bytes());
+        assert_eq!(attribute[0].1.string(), "status");
+        assert_eq!(fields(attribute[1].1.bytes())[0].1.string(), "500");
+
+        // Metric { name, description, gauge { data_points } }, no start time
+        let gauge = metric("queue_depth");
+        assert_eq!(gauge[2].0, 5);
+        let point = fields(fields(gauge[2].1.bytes())[0].1.bytes());
+        assert_eq!(point[0].0, 3);
+        assert_eq!(point[1], (4, Field::Fixed64(2.0f64.to_bits())));
+    }
+}
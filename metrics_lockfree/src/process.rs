@@ -0,0 +1,232 @@
+use crate::{
+    registry::Registry,
+    snapshot::{FamilySnapshot, SampleSnapshot},
+    types::MetricType,
+};
+use std::{fs, io::Result};
+
+// register the process collector to the global registry. fails with AlreadyExists when called
+// twice, or when the registry already has one of its metrics
+pub fn register() -> Result<()> {
+    register_in(&Registry::global())
+}
+
+pub fn register_in(registry: &Registry) -> Result<()> {
+    registry.register(metrics)
+}
+
+// same metrics as the official prometheus clients, read from /proc at scrape time. a file that
+// can't be read only drops the metrics it provides
+pub fn metrics() -> Vec<prometheus::proto::MetricFamily> {
+    let mut families = vec![];
+
+    if let Some(stat) = read_stat() {
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+
+        families.push(family(
+            "process_cpu_seconds_total",
+            "Total user and system CPU time spent in seconds.",
+            MetricType::Counter,
+            (stat.utime + stat.stime) as f64 / ticks,
+        ));
+
+        if let Some(boot_time) = read_boot_time() {
+            families.push(family(
+                "process_start_time_seconds",
+                "Start time of the process since unix epoch in seconds.",
+                MetricType::Gauge,
+                boot_time as f64 + stat.start_time as f64 / ticks,
+            ));
+        }
+    }
+
+    if let Ok(status) = fs::read_to_string("/proc/self/status") {
+        for (key, name, help) in [
+            (
+                "VmRSS:",
+                "process_resident_memory_bytes",
+                "Resident memory size in bytes.",
+            ),
+            (
+                "VmSize:",
+                "process_virtual_memory_bytes",
+                "Virtual memory size in bytes.",
+            ),
+        ] {
+            if let Some(kb) = status_value(&status, key) {
+                families.push(family(name, help, MetricType::Gauge, (kb * 1024) as f64));
+            }
+        }
+
+        if let Some(threads) = status_value(&status, "Threads:") {
+            families.push(family(
+                "process_threads",
+                "Number of OS threads in the process.",
+                MetricType::Gauge,
+                threads as f64,
+            ));
+        }
+    }
+
+    if let Ok(fds) = fs::read_dir("/proc/self/fd") {
+        // the directory handle used to list the fds is counted as well
+        families.push(family(
+            "process_open_fds",
+            "Number of open file descriptors.",
+            MetricType::Gauge,
+            fds.count().saturating_sub(1) as f64,
+        ));
+    }
+
+    if let Some(max_fds) = read_max_fds() {
+        families.push(family(
+            "process_max_fds",
+            "Maximum number of open file descriptors.",
+            MetricType::Gauge,
+            max_fds as f64,
+        ));
+    }
+
+    families
+}
+
+fn family(
+    name: &str,
+    help: &str,
+    metric_type: MetricType,
+    value: f64,
+) -> prometheus::proto::MetricFamily {
+    FamilySnapshot {
+        name: name.to_owned(),
+        help: help.to_owned(),
+        metric_type,
+        source: None,
+        samples: vec![SampleSnapshot {
+            labels: vec![],
+            value,
+        }],
+    }
+    .to_metric_family()
+}
+
+struct Stat {
+    utime: u64,
+    stime: u64,
+    // clock ticks since boot
+    start_time: u64,
+}
+
+fn read_stat() -> Option<Stat> {
+    parse_stat(&fs::read_to_string("/proc/self/stat").ok()?)
+}
+
+fn parse_stat(stat: &str) -> Option<Stat> {
+    // the command name is between parenthesis and may contain spaces, fields are counted from
+    // the state, which is the third one
+    let fields: Vec<&str> = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .collect();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+
+    Some(Stat {
+        utime: field(14)?,
+        stime: field(15)?,
+        start_time: field(22)?,
+    })
+}
+
+// seconds since unix epoch
+fn read_boot_time() -> Option<u64> {
+    fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// "Threads:	4" or "VmRSS:	  1234 kB"
+fn status_value(status: &str, key: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(key))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+// "Max open files            1024                 4096                 files"
+fn read_max_fds() -> Option<u64> {
+    fs::read_to_string("/proc/self/limits")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("Max open files"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat_fields() {
+        // the command name may contain spaces and parenthesis
+        let stat = "42 (my (app) x) S 1 42 42 0 -1 4194560 100 0 0 0 25 7 0 0 20 0 3 0 \
+                    1234 1000000 200 18446744073709551615";
+        let stat = parse_stat(stat).unwrap();
+        assert_eq!((stat.utime, stat.stime, stat.start_time), (25, 7, 1234));
+
+        assert!(parse_stat("42 (app) S 1 2").is_none());
+        assert!(parse_stat("42 app S").is_none());
+    }
+
+    #[test]
+    fn status_values() {
+        let status = "Name:\tapp\nVmSize:\t  10240 kB\nVmRSS:\t    512 kB\nThreads:\t4\n";
+        assert_eq!(status_value(status, "VmSize:"), Some(10240));
+        assert_eq!(status_value(status, "VmRSS:"), Some(512));
+        assert_eq!(status_value(status, "Threads:"), Some(4));
+        assert_eq!(status_value(status, "VmSwap:"), None);
+        assert_eq!(status_value("Threads:\tmany\n", "Threads:"), None);
+    }
+
+    #[test]
+    fn process_families() {
+        let registry = Registry::new();
+        register_in(&registry).unwrap();
+        let err = register_in(&registry).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+        let snapshot = registry.snapshot().unwrap();
+        let names: Vec<&str> = snapshot.families.iter().map(|f| f.name.as_str()).collect();
+        for name in [
+            "process_cpu_seconds_total",
+            "process_start_time_seconds",
+            "process_resident_memory_bytes",
+            "process_virtual_memory_bytes",
+            "process_threads",
+            "process_open_fds",
+            "process_max_fds",
+        ] {
+            assert!(names.contains(&name), "{name} missing from {names:?}");
+        }
+        let value = |name| {
+            snapshot
+                .families
+                .iter()
+                .find(|f| f.name == name)
+                .unwrap()
+                .samples[0]
+                .value
+        };
+        assert!(value("process_resident_memory_bytes") > 0.0);
+        assert!(value("process_threads") >= 1.0);
+        assert!(value("process_open_fds") >= 1.0);
+    }
+}
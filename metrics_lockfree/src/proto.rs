@@ -0,0 +1,59 @@
+use protobuf::{CodedOutputStream, ProtobufResult};
+
+// protobuf messages written by hand, for the push exporters that have no generated code
+
+// nested messages are encoded on their own and written as length delimited bytes
+pub(crate) fn message<F>(write: F) -> ProtobufResult<Vec<u8>>
+where
+    F: FnOnce(&mut CodedOutputStream) -> ProtobufResult<()>,
+{
+    let mut buffer = vec![];
+    {
+        let mut os = CodedOutputStream::vec(&mut buffer);
+        write(&mut os)?;
+        os.flush()?;
+    }
+    Ok(buffer)
+}
+
+// fields of an encoded message for the tests, in the order they were written
+#[cfg(all(test, any(feature = "otlp", feature = "remote-write")))]
+pub(crate) fn fields(buffer: &[u8]) -> Vec<(u32, Field)> {
+    use protobuf::{wire_format::WireType, CodedInputStream};
+
+    let mut is = CodedInputStream::from_bytes(buffer);
+    let mut fields = vec![];
+    while !is.eof().unwrap() {
+        let (number, wire_type) = is.read_tag_unpack().unwrap();
+        let field = match wire_type {
+            WireType::WireTypeVarint => Field::Varint(is.read_raw_varint64().unwrap()),
+            WireType::WireTypeFixed64 => Field::Fixed64(is.read_fixed64().unwrap()),
+            WireType::WireTypeLengthDelimited => Field::Bytes(is.read_bytes().unwrap()),
+            wire_type => panic!("Unexpected wire type {wire_type:?}"),
+        };
+        fields.push((number, field));
+    }
+    fields
+}
+
+#[cfg(all(test, any(feature = "otlp", feature = "remote-write")))]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Field {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(Vec<u8>),
+}
+
+#[cfg(all(test, any(feature = "otlp", feature = "remote-write")))]
+impl Field {
+    pub(crate) fn bytes(&self) -> &[u8] {
+        match self {
+            Field::Bytes(bytes) => bytes,
+            field => panic!("Not a length delimited field: {field:?}"),
+        }
+    }
+
+    pub(crate) fn string(&self) -> &str {
+        std::str::from_utf8(self.bytes()).unwrap()
+    }
+}
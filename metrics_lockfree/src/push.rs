@@ -0,0 +1,61 @@
+use std::{
+    io::Result,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// running push exporter, the metrics are flushed one last time and the thread stopped when the
+// handle is dropped
+#[must_use = "the exporter is stopped when its handle is dropped"]
+pub struct PushHandle {
+    // closed to stop the flush thread
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// call `flush` every interval on a thread, and once more when the handle is dropped. it is told
+// when the flush is the last one, the drop waits for it and shouldn't block for long
+pub(crate) fn spawn<F>(thread_name: &str, interval: Duration, mut flush: F) -> Result<PushHandle>
+where
+    F: FnMut(bool) + Send + 'static,
+{
+    let (stop, stopped) = mpsc::channel::<()>();
+
+    let thread = thread::Builder::new()
+        .name(thread_name.to_owned())
+        .spawn(move || loop {
+            // woken up early when the handle is dropped
+            let last = !matches!(
+                stopped.recv_timeout(interval),
+                Err(RecvTimeoutError::Timeout)
+            );
+
+            flush(last);
+
+            if last {
+                break;
+            }
+        })?;
+
+    Ok(PushHandle {
+        stop: Some(stop),
+        thread: Some(thread),
+    })
+}
+
+impl PushHandle {
+    // flush the metrics one last time and wait for the thread to exit
+    pub fn shutdown(self) {
+        // everything is done on drop
+    }
+}
+
+impl Drop for PushHandle {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
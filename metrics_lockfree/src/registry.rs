@@ -0,0 +1,424 @@
+use crate::{
+    self_metrics::{self, SelfMetricsSet},
+    snapshot::{FamilySnapshot, MetricsSnapshot, SampleSnapshot},
+    types::MetricType,
+};
+use ahash::{HashMap, HashMapExt};
+use prometheus::proto::MetricFamily;
+use std::{
+    any::{Any, TypeId},
+    io::{Error, ErrorKind, Result},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex, PoisonError, RwLock, Weak,
+    },
+    time::SystemTime,
+};
+
+// called at scrape time, may capture state
+pub type ExportFn = Box<dyn Fn() -> Vec<MetricFamily> + Send + Sync>;
+
+struct Registration {
+    id: u64,
+    export: ExportFn,
+    // families returned when registered, no other registration may use them
+    names: Vec<String>,
+    // derived structs give their name and the number of thread handles they built
+    name: Option<&'static str>,
+    threads: Option<Box<dyn Fn() -> usize + Send + Sync>>,
+}
+
+// set of metrics served by an exporter. structs built with `new()` and the `Exporter::register`
+// functions use the global registry, `new_in(&registry)` keeps them apart
+pub struct Registry {
+    // shared with the registration handles
+    registrations: Arc<RwLock<Vec<Registration>>>,
+    // per thread values of the derived structs built in this registry, by factory type. the
+    // metric handles keep their own values alive, so they can outlive the registry
+    factories: Mutex<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+    created: SystemTime,
+}
+
+static GLOBAL_REGISTRY: LazyLock<Arc<Registry>> = LazyLock::new(|| Arc::new(Registry::new()));
+
+static NEXT_REGISTRATION_ID: AtomicU64 = AtomicU64::new(0);
+
+// returned by the scoped registrations: the metrics are unregistered when the handle is
+// dropped, or when `keep` was called as long as the registry exists
+#[must_use = "the metrics are unregistered when the handle is dropped"]
+pub struct RegistrationHandle {
+    registrations: Weak<RwLock<Vec<Registration>>>,
+    id: u64,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            registrations: Arc::new(RwLock::new(Vec::new())),
+            factories: Mutex::new(HashMap::new()),
+            created: SystemTime::now(),
+        }
+    }
+
+    pub fn global() -> Arc<Registry> {
+        GLOBAL_REGISTRY.clone()
+    }
+
+    // the derived struct counters built in the registry count from this time, their totals are
+    // kept when thread handles are dropped
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    // `f` is called once right away to learn the families it exports, registering a family
+    // name already exported by another registration is an error, and so is a family that is
+    // not a counter or a gauge: the snapshots served by the exporter have no other types. the
+    // metrics stay registered as long as the registry
+    pub fn register<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn() -> Vec<MetricFamily> + Send + Sync + 'static,
+    {
+        self.register_scoped(f).map(RegistrationHandle::keep)
+    }
+
+    // same as `register`, the metrics are unregistered when the returned handle is dropped
+    pub fn register_scoped<F>(&self, f: F) -> Result<RegistrationHandle>
+    where
+        F: Fn() -> Vec<MetricFamily> + Send + Sync + 'static,
+    {
+        self.register_with(Box::new(f), None, None)
+    }
+
+    // name and thread count feed the exporter own metrics
+    pub fn register_struct<F, T>(&self, name: &'static str, f: F, threads: T) -> Result<()>
+    where
+        F: Fn() -> Vec<MetricFamily> + Send + Sync + 'static,
+        T: Fn() -> usize + Send + Sync + 'static,
+    {
+        self.register_with(Box::new(f), Some(name), Some(Box::new(threads)))
+            .map(RegistrationHandle::keep)
+    }
+
+    // gauge whose value is computed by `f` at scrape time, for values that are cheap to compute
+    // on demand such as a queue length or a cache size
+    pub fn gauge_fn<F>(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[(String, String)],
+        f: F,
+    ) -> Result<()>
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        self.gauge_fn_scoped(name, help, labels, f)
+            .map(RegistrationHandle::keep)
+    }
+
+    // same as `gauge_fn`, the gauge is unregistered when the returned handle is dropped
+    pub fn gauge_fn_scoped<F>(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[(String, String)],
+        f: F,
+    ) -> Result<RegistrationHandle>
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        let name = name.to_owned();
+        let help = help.to_owned();
+        let labels = labels.to_vec();
+
+        self.register_scoped(move || {
+            vec![FamilySnapshot {
+                name: name.clone(),
+                help: help.clone(),
+                metric_type: MetricType::Gauge,
+                source: None,
+                samples: vec![SampleSnapshot {
+                    labels: labels.clone(),
+                    value: f(),
+                }],
+            }
+            .to_metric_family()]
+        })
+    }
+
+    // used by the derive macro: factory of a struct in this registry, created and registered
+    // the first time the struct is built in it. it stays registered as long as the registry
+    pub fn struct_factory<T>(
+        &self,
+        name: &'static str,
+        init: fn() -> T,
+        export: fn(&T) -> Vec<MetricFamily>,
+        threads: fn(&T) -> usize,
+    ) -> Result<Arc<RwLock<T>>>
+    where
+        T: Send + Sync + 'static,
+    {
+        let mut factories = self
+            .factories
+            .lock()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Poison error: {e}")))?;
+        if let Some(factory) = factories.get(&TypeId::of::<T>()) {
+            return Ok(downcast_factory(factory.clone()));
+        }
+
+        // the registration owns the factory, which is dropped with the registry, or right away
+        // when it fails to register
+        let factory = Arc::new(RwLock::new(init()));
+        let (exported, counted) = (factory.clone(), factory.clone());
+        self.register_struct(
+            name,
+            move || exported.read().map(|f| export(&f)).unwrap_or_default(),
+            move || counted.read().map(|f| threads(&f)).unwrap_or(0),
+        )?;
+        factories.insert(TypeId::of::<T>(), factory.clone());
+
+        Ok(factory)
+    }
+
+    // factory of a struct already built in this registry
+    pub fn find_struct_factory<T>(&self) -> Option<Arc<RwLock<T>>>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.factories
+            .lock()
+            .ok()?
+            .get(&TypeId::of::<T>())
+            .map(|factory| downcast_factory(factory.clone()))
+    }
+
+    // current values of every registered metric, the same ones an exporter serves
+    pub fn snapshot(&self) -> Result<MetricsSnapshot> {
+        self.collect(None)
+    }
+
+    fn register_with(
+        &self,
+        export: ExportFn,
+        name: Option<&'static str>,
+        threads: Option<Box<dyn Fn() -> usize + Send + Sync>>,
+    ) -> Result<RegistrationHandle> {
+        let families = export();
+        if let Some(family) = families.iter().find(|family| {
+            !matches!(
+                family.get_field_type(),
+                prometheus::proto::MetricType::COUNTER | prometheus::proto::MetricType::GAUGE
+            )
+        }) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Metric {} is a {:?}, only counters and gauges are supported",
+                    family.get_name(),
+                    family.get_field_type()
+                ),
+            ));
+        }
+
+        let mut names: Vec<String> = families
+            .iter()
+            .map(|family| family.get_name().to_owned())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+
+        let mut registrations = self
+            .registrations
+            .write()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Poison error: {e}")))?;
+
+        if let Some(name) = names
+            .iter()
+            .find(|name| registrations.iter().any(|r| r.names.contains(name)))
+        {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("Metric {name} is already registered"),
+            ));
+        }
+
+        let id = NEXT_REGISTRATION_ID.fetch_add(1, Ordering::Relaxed);
+        registrations.push(Registration {
+            id,
+            export,
+            names,
+            name,
+            threads,
+        });
+
+        Ok(RegistrationHandle {
+            registrations: Arc::downgrade(&self.registrations),
+            id,
+        })
+    }
+
+    // fill the snapshot calling all metrics functions, followed by the own metrics of the
+    // exporter when given
+    pub(crate) fn collect(&self, self_metrics: Option<&SelfMetricsSet>) -> Result<MetricsSnapshot> {
+        match self.registrations.read() {
+            Ok(registrations) => {
+                let mut snapshot = MetricsSnapshot::default();
+                // export functions may return families they did not return when registered,
+                // the first registration to return a family name owns it
+                let mut owners: HashMap<String, u64> = HashMap::new();
+                registrations.iter().for_each(|r| {
+                    let metric_families: Vec<MetricFamily> = (r.export)()
+                        .into_iter()
+                        .filter(|m| *owners.entry(m.get_name().to_owned()).or_insert(r.id) == r.id)
+                        .collect();
+
+                    let mut families = MetricsSnapshot::from(metric_families).families;
+                    families
+                        .iter_mut()
+                        .for_each(|f| f.source = r.name.map(str::to_owned));
+                    snapshot.families.extend(families);
+                });
+
+                if let Some(set) = self_metrics {
+                    let structs: Vec<(&str, Option<usize>)> = registrations
+                        .iter()
+                        .filter_map(|r| r.name.map(|name| (name, r.threads.as_ref().map(|f| f()))))
+                        .collect();
+                    self_metrics::collect(&mut snapshot, &structs, set);
+                }
+
+                Ok(snapshot)
+            }
+            Err(e) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Poison error: {e}"),
+            )),
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn downcast_factory<T>(factory: Arc<dyn Any + Send + Sync>) -> Arc<RwLock<T>>
+where
+    T: Send + Sync + 'static,
+{
+    factory
+        .downcast()
+        .expect("factory is stored under its own type id. this should never fail")
+}
+
+impl RegistrationHandle {
+    // keep the metrics registered as long as the registry exists
+    pub fn keep(mut self) {
+        self.registrations = Weak::new();
+    }
+}
+
+impl Drop for RegistrationHandle {
+    fn drop(&mut self) {
+        if let Some(registrations) = self.registrations.upgrade() {
+            // a poisoned lock can't leave the vector half modified
+            registrations
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|r| r.id != self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::family;
+    use std::sync::atomic::AtomicUsize;
+
+    fn names(registry: &Registry) -> Vec<String> {
+        registry
+            .snapshot()
+            .unwrap()
+            .families
+            .into_iter()
+            .map(|f| f.name)
+            .collect()
+    }
+
+    #[test]
+    fn persistent_and_scoped() {
+        let registry = Registry::new();
+        registry.gauge_fn("kept", "help", &[], || 1.0).unwrap();
+        let handle = registry
+            .gauge_fn_scoped("scoped", "help", &[], || 1.0)
+            .unwrap();
+        assert_eq!(names(&registry), ["kept", "scoped"]);
+
+        drop(handle);
+        assert_eq!(names(&registry), ["kept"]);
+
+        registry
+            .gauge_fn_scoped("scoped", "help", &[], || 1.0)
+            .unwrap()
+            .keep();
+        assert_eq!(names(&registry), ["kept", "scoped"]);
+    }
+
+    #[test]
+    fn unsupported_types() {
+        let registry = Registry::new();
+        let err = registry
+            .register(|| {
+                let mut histogram = MetricFamily::new();
+                histogram.set_name("latency".to_owned());
+                histogram.set_field_type(prometheus::proto::MetricType::HISTOGRAM);
+                vec![
+                    family("requests", MetricType::Counter, &[(&[], 1.0)]).to_metric_family(),
+                    histogram,
+                ]
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("latency"), "{err}");
+        assert!(names(&registry).is_empty());
+    }
+
+    #[test]
+    fn name_collision() {
+        let registry = Registry::new();
+        registry.gauge_fn("queue", "help", &[], || 1.0).unwrap();
+
+        let err = registry.gauge_fn("queue", "help", &[], || 2.0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(registry
+            .gauge_fn_scoped("queue", "help", &[], || 2.0)
+            .is_err());
+        assert_eq!(names(&registry), ["queue"]);
+    }
+
+    static FACTORIES_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Factory;
+
+    impl Drop for Factory {
+        fn drop(&mut self) {
+            FACTORIES_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn export(_: &Factory) -> Vec<MetricFamily> {
+        vec![family("queue", MetricType::Gauge, &[]).to_metric_family()]
+    }
+
+    #[test]
+    fn failed_factory_is_dropped() {
+        let registry = Registry::new();
+        registry.gauge_fn("queue", "help", &[], || 1.0).unwrap();
+
+        let res = registry.struct_factory("Factory", || Factory, export, |_| 0);
+        assert!(res.is_err());
+        assert_eq!(FACTORIES_DROPPED.load(Ordering::Relaxed), 1);
+        assert!(registry.find_struct_factory::<Factory>().is_none());
+    }
+}
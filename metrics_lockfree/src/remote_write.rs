@@ -0,0 +1,407 @@
+use crate::{
+    http_client::{self, Backoff, Sent, Url},
+    proto::message,
+    push::{self, PushHandle},
+    registry::Registry,
+    snapshot::MetricsSnapshot,
+    types::MetricType,
+};
+use protobuf::ProtobufResult;
+use std::{
+    collections::VecDeque,
+    io::{Error, Result},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// prometheus.MetricMetadata.MetricType
+const METADATA_COUNTER: i32 = 1;
+const METADATA_GAUGE: i32 = 2;
+
+pub struct RemoteWriteExporter;
+
+pub struct RemoteWriteBuilder {
+    url: Url,
+    registry: Arc<Registry>,
+    headers: Vec<(String, String)>,
+    flush_interval: Duration,
+    timeout: Duration,
+    max_queue: usize,
+    backoff: Backoff,
+    shutdown_timeout: Duration,
+    thread_name: String,
+}
+
+impl RemoteWriteExporter {
+    // push the global registry to a prometheus remote write receiver, such as
+    // http://localhost:9090/api/v1/write
+    pub fn builder(url: &str) -> Result<RemoteWriteBuilder> {
+        Ok(RemoteWriteBuilder {
+            url: Url::parse(url)?,
+            registry: Registry::global(),
+            headers: vec![],
+            flush_interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(10),
+            max_queue: 100,
+            backoff: Backoff {
+                max_retries: 3,
+                min: Duration::from_millis(100),
+                max: Duration::from_secs(5),
+            },
+            shutdown_timeout: Duration::from_secs(5),
+            thread_name: String::from("metrics-remote-write"),
+        })
+    }
+}
+
+impl RemoteWriteBuilder {
+    pub fn registry(mut self, registry: Arc<Registry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    // extra header of every request, such as the authorization asked by the receiver
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    // connect, write and read timeout of http requests
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // collections kept while the receiver can't be reached, the oldest one is dropped when a
+    // new one doesn't fit
+    pub fn max_queue(mut self, max_queue: usize) -> Self {
+        self.max_queue = max_queue.max(1);
+        self
+    }
+
+    // retries of a failed request before it is left in the queue for the next flush
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.backoff.max_retries = max_retries;
+        self
+    }
+
+    // the wait before a retry starts at min and doubles up to max
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.backoff.min = min;
+        self.backoff.max = max.max(min);
+        self
+    }
+
+    // the last flush, when the handle is dropped, sends the queued requests once without
+    // retries and gives up on the ones left after this long
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn thread_name(mut self, name: &str) -> Self {
+        self.thread_name = name.to_owned();
+        self
+    }
+
+    pub fn start(self) -> Result<PushHandle> {
+        // encoded write requests waiting to be sent, oldest first
+        let mut queue: VecDeque<Vec<u8>> = VecDeque::new();
+        let thread_name = self.thread_name.clone();
+        let flush_interval = self.flush_interval;
+
+        push::spawn(&thread_name, flush_interval, move |last| {
+            let deadline = Instant::now() + self.shutdown_timeout;
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or(0);
+
+            let body = self.registry.snapshot().and_then(|snapshot| {
+                write_request(&snapshot, timestamp)
+                    .map_err(|e| Error::other(format!("Protobuf error: {e}")))
+            });
+            let compressed = body.and_then(|body| {
+                snap::raw::Encoder::new()
+                    .compress_vec(&body)
+                    .map_err(|e| Error::other(format!("Snappy error: {e}")))
+            });
+            if let Ok(compressed) = compressed {
+                if queue.len() >= self.max_queue {
+                    queue.pop_front();
+                }
+                queue.push_back(compressed);
+            }
+
+            // in order, the samples of a series must be written with increasing timestamps
+            while let Some(body) = queue.front() {
+                let sent = if last {
+                    // each request gets what is left of the shutdown timeout
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        break;
+                    }
+                    self.send(body, left.min(self.timeout))
+                } else {
+                    http_client::with_retries(&self.backoff, || self.send(body, self.timeout))
+                };
+                match sent {
+                    Sent::Done | Sent::Rejected => {
+                        queue.pop_front();
+                    }
+                    Sent::Retry => break,
+                }
+            }
+        })
+    }
+
+    fn send(&self, body: &[u8], timeout: Duration) -> Sent {
+        let mut headers = vec![
+            ("Content-Type", "application/x-protobuf"),
+            ("Content-Encoding", "snappy"),
+            ("X-Prometheus-Remote-Write-Version", "0.1.0"),
+        ];
+        headers.extend(self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+
+        match http_client::post(&self.url, &headers, body, timeout) {
+            Ok(response) => match response.status {
+                200..=299 => Sent::Done,
+                429 | 500..=599 => Sent::Retry,
+                _ => Sent::Rejected,
+            },
+            Err(_) => Sent::Retry,
+        }
+    }
+}
+
+// WriteRequest { timeseries = 1, metadata = 3 }, every sample at the collection timestamp
+fn write_request(snapshot: &MetricsSnapshot, timestamp: i64) -> ProtobufResult<Vec<u8>> {
+    message(|os| {
+        for family in &snapshot.families {
+            for sample in &family.samples {
+                // TimeSeries { labels = 1, samples = 2 }, labels sorted by name. a label named
+                // __name__ is dropped, the series would have two names
+                let mut labels: Vec<(&str, &str)> = sample
+                    .labels
+                    .iter()
+                    .filter(|(k, _)| k != "__name__")
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect();
+                labels.push(("__name__", &family.name));
+                labels.sort_unstable();
+
+                os.write_bytes(
+                    1,
+                    &message(|os| {
+                        labels.iter().try_for_each(|(k, v)| {
+                            // Label { name, value }
+                            os.write_bytes(
+                                1,
+                                &message(|os| {
+                                    os.write_string(1, k)?;
+                                    os.write_string(2, v)
+                                })?,
+                            )
+                        })?;
+                        // Sample { value, timestamp }
+                        os.write_bytes(
+                            2,
+                            &message(|os| {
+                                os.write_double(1, sample.value)?;
+                                os.write_int64(2, timestamp)
+                            })?,
+                        )
+                    })?,
+                )?;
+            }
+        }
+
+        snapshot.families.iter().try_for_each(|family| {
+            // MetricMetadata { type, metric_family_name, help }
+            os.write_bytes(
+                3,
+                &message(|os| {
+                    os.write_enum(
+                        1,
+                        match family.metric_type {
+                            MetricType::Counter => METADATA_COUNTER,
+                            MetricType::Gauge => METADATA_GAUGE,
+                        },
+                    )?;
+                    os.write_string(2, &family.name)?;
+                    os.write_string(4, &family.help)
+                })?,
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http_client::mock,
+        proto::{fields, Field},
+        snapshot::{family, FamilySnapshot},
+    };
+
+    // fields of a message
+    type Message = Vec<(u32, Field)>;
+
+    // label names and values of a TimeSeries, and its samples
+    fn series(buffer: &[u8]) -> (Vec<(String, String)>, Vec<Message>) {
+        let mut labels = vec![];
+        let mut samples = vec![];
+        for (number, field) in fields(buffer) {
+            match number {
+                1 => {
+                    let label = fields(field.bytes());
+                    labels.push((
+                        label[0].1.string().to_owned(),
+                        label[1].1.string().to_owned(),
+                    ));
+                }
+                2 => samples.push(fields(field.bytes())),
+                n => panic!("Unexpected TimeSeries field {n}"),
+            }
+        }
+        (labels, samples)
+    }
+
+    #[test]
+    fn snappy_write_request() {
+        let registry = Arc::new(Registry::new());
+        registry
+            .register(|| {
+                let labels = [("status", "500"), ("__name__", "other"), ("method", "get")];
+                vec![FamilySnapshot {
+                    help: "handled requests".to_owned(),
+                    ..family("requests", MetricType::Counter, &[(&labels, 5.0)])
+                }
+                .to_metric_family()]
+            })
+            .unwrap();
+        registry
+            .gauge_fn("queue_depth", "help", &[], || 2.0)
+            .unwrap();
+
+        // flushed once when the handle is dropped, the 503 isn't retried
+        let (url, requests) = mock::receiver(vec![503]);
+        let handle = RemoteWriteExporter::builder(&url)
+            .unwrap()
+            .registry(registry)
+            .flush_interval(Duration::from_secs(3600))
+            .backoff(Duration::from_millis(1), Duration::from_millis(1))
+            .start()
+            .unwrap();
+        drop(handle);
+        let request = requests.recv().unwrap();
+        assert!(requests.recv_timeout(Duration::from_millis(300)).is_err());
+
+        assert_eq!(
+            request.header("content-type"),
+            Some("application/x-protobuf")
+        );
+        assert_eq!(request.header("content-encoding"), Some("snappy"));
+        assert_eq!(
+            request.header("user-agent"),
+            Some(concat!("metrics_lockfree/", env!("CARGO_PKG_VERSION")))
+        );
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&request.body)
+            .unwrap();
+
+        // WriteRequest { timeseries = 1, metadata = 3 }
+        let message = fields(&body);
+        let timeseries: Vec<_> = message
+            .iter()
+            .filter(|(n, _)| *n == 1)
+            .map(|(_, f)| series(f.bytes()))
+            .collect();
+        assert_eq!(timeseries.len(), 2);
+        let (labels, samples) = timeseries
+            .iter()
+            .find(|(labels, _)| labels[0].1 == "requests")
+            .unwrap();
+        let expected: Vec<(String, String)> = [
+            ("__name__", "requests"),
+            ("method", "get"),
+            ("status", "500"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(*labels, expected);
+        // Sample { value, timestamp }
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0][0], (1, Field::Fixed64(5.0f64.to_bits())));
+        assert!(matches!(samples[0][1], (2, Field::Varint(ms)) if ms > 0));
+
+        let (labels, samples) = timeseries
+            .iter()
+            .find(|(labels, _)| labels[0].1 == "queue_depth")
+            .unwrap();
+        assert_eq!(labels.len(), 1);
+        assert_eq!(samples[0][0], (1, Field::Fixed64(2.0f64.to_bits())));
+
+        // MetricMetadata { type, metric_family_name, help }
+        let metadata: Vec<_> = message
+            .iter()
+            .filter(|(n, _)| *n == 3)
+            .map(|(_, f)| fields(f.bytes()))
+            .collect();
+        assert_eq!(metadata.len(), 2);
+        let requests = metadata
+            .iter()
+            .find(|m| m[1].1.string() == "requests")
+            .unwrap();
+        assert_eq!(requests[0], (1, Field::Varint(METADATA_COUNTER as u64)));
+        assert_eq!(requests[2], (4, Field::Bytes(b"handled requests".to_vec())));
+    }
+
+    // collection timestamp of a write request
+    fn timestamp(request: &mock::Request) -> u64 {
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&request.body)
+            .unwrap();
+        let (_, field) = fields(&body).into_iter().find(|(n, _)| *n == 1).unwrap();
+        match series(field.bytes()).1[0][1] {
+            (2, Field::Varint(ms)) => ms,
+            ref field => panic!("Unexpected Sample field {field:?}"),
+        }
+    }
+
+    #[test]
+    fn queue_while_receiver_is_down() {
+        let registry = Arc::new(Registry::new());
+        registry.gauge_fn("up", "help", &[], || 1.0).unwrap();
+
+        // down for the first four flushes, each one sends the oldest request once
+        let (url, requests) = mock::receiver(vec![503; 4]);
+        let handle = RemoteWriteExporter::builder(&url)
+            .unwrap()
+            .registry(registry)
+            .flush_interval(Duration::from_millis(30))
+            .max_queue(2)
+            .max_retries(0)
+            .start()
+            .unwrap();
+        let received: Vec<u64> = (0..6)
+            .map(|_| timestamp(&requests.recv_timeout(Duration::from_secs(5)).unwrap()))
+            .collect();
+        drop(handle);
+
+        // collections 1 to 5: the first one is sent again by the second flush, then dropped
+        // for the third one. once the receiver is back the queue is sent oldest first
+        let [c1, c1_again, c2, c3, c4, c5] = received[..] else {
+            unreachable!()
+        };
+        assert_eq!(c1, c1_again);
+        assert!(c1 < c2 && c2 < c3 && c3 < c4 && c4 < c5, "{received:?}");
+    }
+}
@@ -0,0 +1,312 @@
+use crate::{
+    counter::{Counter, CounterPin},
+    snapshot::{FamilySnapshot, MetricsSnapshot, SampleSnapshot},
+    types::{MetricType, Tags},
+};
+use std::{
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+// distinct status codes answered to scrapes, 0 is the tagless value
+const MAX_CODES: usize = 16;
+
+// per thread values of the exporter own metrics, built the same way as derived structs
+#[derive(Default)]
+struct SelfMetricsValues {
+    // owned by a live exporter thread. the values of a released slot are kept, so that the
+    // counters never go down, and the slot is reused by the next thread
+    in_use: bool,
+    scrape_requests: CounterPin<MAX_CODES>,
+    // responses that couldn't be sent, the scraper is most likely gone
+    scrape_errors: CounterPin<1>,
+    // nanoseconds
+    scrape_duration_sum: CounterPin<1>,
+    scrape_duration_count: CounterPin<1>,
+}
+
+// values of the exporter own metrics, each exporter has its own
+pub(crate) struct SelfMetricsSet {
+    values: RwLock<Vec<SelfMetricsValues>>,
+    scrape_requests_tags: Arc<RwLock<Tags>>,
+}
+
+// handle owned by each exporter thread answering requests, its slot is released on drop
+pub(crate) struct SelfMetrics {
+    set: Arc<SelfMetricsSet>,
+    slot: usize,
+    scrape_requests: Counter<MAX_CODES>,
+    scrape_errors: Counter,
+    scrape_duration_sum: Counter,
+    scrape_duration_count: Counter,
+}
+
+unsafe impl Send for SelfMetrics {}
+
+impl SelfMetricsSet {
+    pub(crate) fn new() -> Self {
+        Self {
+            values: RwLock::new(Vec::new()),
+            scrape_requests_tags: Arc::new(RwLock::new(Tags::new(MAX_CODES))),
+        }
+    }
+
+    pub(crate) fn handle(self: &Arc<Self>) -> SelfMetrics {
+        // a poisoned lock can't leave the vector half pushed
+        let mut values = self.values.write().unwrap_or_else(PoisonError::into_inner);
+        let slot = match values.iter().position(|v| !v.in_use) {
+            Some(slot) => slot,
+            None => {
+                values.push(SelfMetricsValues::default());
+                values.len() - 1
+            }
+        };
+        let values = &mut values[slot];
+        values.in_use = true;
+
+        SelfMetrics {
+            set: self.clone(),
+            slot,
+            scrape_requests: Counter::from(&mut values.scrape_requests)
+                .set_tags(self.scrape_requests_tags.clone()),
+            scrape_errors: Counter::from(&mut values.scrape_errors),
+            scrape_duration_sum: Counter::from(&mut values.scrape_duration_sum),
+            scrape_duration_count: Counter::from(&mut values.scrape_duration_count),
+        }
+    }
+}
+
+impl SelfMetrics {
+    pub(crate) fn scrape_answered(&mut self, code: u16) {
+        self.scrape_requests
+            .add(1, Some(&[("code".to_string(), code.to_string())]));
+    }
+
+    pub(crate) fn scrape_failed(&mut self) {
+        self.scrape_errors.add(1, None);
+    }
+
+    pub(crate) fn scrape_duration(&mut self, duration: Duration) {
+        self.scrape_duration_sum
+            .add(duration.as_nanos() as u64, None);
+        self.scrape_duration_count.add(1, None);
+    }
+}
+
+impl Drop for SelfMetrics {
+    fn drop(&mut self) {
+        let mut values = self
+            .set
+            .values
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        values[self.slot].in_use = false;
+    }
+}
+
+fn counter(name: &str, help: &str, value: f64) -> FamilySnapshot {
+    FamilySnapshot {
+        name: name.to_owned(),
+        help: help.to_owned(),
+        metric_type: MetricType::Counter,
+        source: None,
+        samples: vec![SampleSnapshot {
+            labels: vec![],
+            value,
+        }],
+    }
+}
+
+fn gauge(name: &str, help: &str, samples: Vec<SampleSnapshot>) -> FamilySnapshot {
+    FamilySnapshot {
+        name: name.to_owned(),
+        help: help.to_owned(),
+        metric_type: MetricType::Gauge,
+        source: None,
+        samples,
+    }
+}
+
+// exporter metrics, appended to the snapshot of the registered structs. `structs` is the name
+// and number of thread handles of every registered struct
+pub(crate) fn collect(
+    snapshot: &mut MetricsSnapshot,
+    structs: &[(&str, Option<usize>)],
+    set: &SelfMetricsSet,
+) {
+    let mut families = vec![];
+
+    // label sets of the registered metrics, before adding our own
+    families.push(gauge(
+        "metrics_lockfree_label_sets",
+        "Number of label sets allocated per metric",
+        snapshot
+            .families
+            .iter()
+            .map(|f| SampleSnapshot {
+                labels: vec![("metric".to_string(), f.name.clone())],
+                value: f.samples.iter().filter(|s| !s.labels.is_empty()).count() as f64,
+            })
+            .collect(),
+    ));
+
+    families.push(gauge(
+        "metrics_lockfree_registered_structs",
+        "Number of metrics structs registered to the exporter",
+        vec![SampleSnapshot {
+            labels: vec![],
+            value: structs.len() as f64,
+        }],
+    ));
+
+    families.push(gauge(
+        "metrics_lockfree_thread_handles",
+        "Number of thread handles built per metrics struct",
+        structs
+            .iter()
+            .filter_map(|(name, threads)| {
+                threads.map(|threads| SampleSnapshot {
+                    labels: vec![("struct".to_string(), name.to_string())],
+                    value: threads as f64,
+                })
+            })
+            .collect(),
+    ));
+
+    if let Ok(values) = set.values.read() {
+        let sum = |f: fn(&SelfMetricsValues) -> &CounterPin<1>| -> u64 {
+            values.iter().map(|v| f(v).get(0)).sum()
+        };
+
+        // the average duration over an interval is the rate of the sum over the rate of the count
+        families.push(counter(
+            "metrics_lockfree_scrape_duration_seconds_sum",
+            "Total time spent answering scrapes",
+            sum(|v| &v.scrape_duration_sum) as f64 / 1e9,
+        ));
+        families.push(counter(
+            "metrics_lockfree_scrape_duration_seconds_count",
+            "Number of scrapes timed in the duration sum",
+            sum(|v| &v.scrape_duration_count) as f64,
+        ));
+        families.push(counter(
+            "metrics_lockfree_scrape_errors_total",
+            "Number of scrape responses that couldn't be sent",
+            sum(|v| &v.scrape_errors) as f64,
+        ));
+
+        families.push(FamilySnapshot {
+            name: "metrics_lockfree_scrape_requests_total".to_owned(),
+            help: "Number of scrapes answered, by status code".to_owned(),
+            metric_type: MetricType::Counter,
+            source: None,
+            samples: set
+                .scrape_requests_tags
+                .read()
+                .unwrap()
+                .tags()
+                .iter()
+                .map(|(labels, id)| SampleSnapshot {
+                    labels: labels.clone(),
+                    value: values
+                        .iter()
+                        .map(|v| v.scrape_requests.get(*id))
+                        .sum::<u64>() as f64,
+                })
+                .collect(),
+        });
+    }
+
+    snapshot.families.extend(families);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(snapshot: &MetricsSnapshot, name: &str) -> f64 {
+        snapshot
+            .families
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| f.samples.iter().map(|s| s.value).sum())
+            .unwrap_or_default()
+    }
+
+    fn collected(set: &SelfMetricsSet) -> MetricsSnapshot {
+        let mut snapshot = MetricsSnapshot { families: vec![] };
+        collect(&mut snapshot, &[], set);
+        snapshot
+    }
+
+    #[test]
+    fn duration_sum_and_count() {
+        let set = Arc::new(SelfMetricsSet::new());
+        let mut metrics = set.handle();
+        metrics.scrape_duration(Duration::from_millis(1500));
+        metrics.scrape_duration(Duration::from_millis(500));
+        metrics.scrape_failed();
+        metrics.scrape_answered(200);
+        drop(metrics);
+        // kept once the handle is released
+        let mut metrics = set.handle();
+        metrics.scrape_answered(200);
+        metrics.scrape_answered(503);
+
+        let snapshot = collected(&set);
+        assert_eq!(
+            value(&snapshot, "metrics_lockfree_scrape_duration_seconds_sum"),
+            2.0
+        );
+        assert_eq!(
+            value(&snapshot, "metrics_lockfree_scrape_duration_seconds_count"),
+            2.0
+        );
+        assert_eq!(
+            value(&snapshot, "metrics_lockfree_scrape_errors_total"),
+            1.0
+        );
+        let requests = snapshot
+            .families
+            .iter()
+            .find(|f| f.name == "metrics_lockfree_scrape_requests_total")
+            .unwrap();
+        let code = |code: &str| {
+            requests
+                .samples
+                .iter()
+                .find(|s| s.labels == [("code".to_string(), code.to_string())])
+                .map(|s| s.value)
+        };
+        assert_eq!(code("200"), Some(2.0));
+        assert_eq!(code("503"), Some(1.0));
+    }
+
+    #[test]
+    fn sets_are_independent() {
+        let (a, b) = (
+            Arc::new(SelfMetricsSet::new()),
+            Arc::new(SelfMetricsSet::new()),
+        );
+        a.handle().scrape_failed();
+        assert_eq!(
+            value(&collected(&a), "metrics_lockfree_scrape_errors_total"),
+            1.0
+        );
+        assert_eq!(
+            value(&collected(&b), "metrics_lockfree_scrape_errors_total"),
+            0.0
+        );
+    }
+
+    #[test]
+    fn slots_are_reused() {
+        let set = Arc::new(SelfMetricsSet::new());
+        let _held = set.handle();
+        for _ in 0..100 {
+            let mut metrics = set.handle();
+            metrics.scrape_answered(200);
+        }
+        assert_eq!(set.values.read().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,338 @@
+use crate::types::MetricType;
+use prometheus::proto::{Counter, Gauge, LabelPair, Metric, MetricFamily};
+use protobuf::RepeatedField;
+
+// format-neutral view of every registered metric at scrape time, this is what encoders consume
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MetricsSnapshot {
+    pub families: Vec<FamilySnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FamilySnapshot {
+    pub name: String,
+    pub help: String,
+    pub metric_type: MetricType,
+    // derived struct the family is a field of
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub source: Option<String>,
+    pub samples: Vec<SampleSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SampleSnapshot {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+impl MetricsSnapshot {
+    pub fn family(&self, name: &str) -> Option<&FamilySnapshot> {
+        self.families.iter().find(|f| f.name == name)
+    }
+
+    // counters as their increase since `previous`, gauges as they are. a counter lower than its
+    // previous value was reset, all of its value is the increase
+    pub fn delta(&self, previous: &MetricsSnapshot) -> MetricsSnapshot {
+        let families = self
+            .families
+            .iter()
+            .map(|family| {
+                let previous = match family.metric_type {
+                    MetricType::Counter => previous.family(&family.name),
+                    MetricType::Gauge => None,
+                };
+
+                FamilySnapshot {
+                    name: family.name.clone(),
+                    help: family.help.clone(),
+                    metric_type: family.metric_type,
+                    source: family.source.clone(),
+                    samples: family
+                        .samples
+                        .iter()
+                        .map(|sample| {
+                            let before = previous
+                                .and_then(|p| p.samples.iter().find(|s| s.labels == sample.labels))
+                                .map(|s| s.value)
+                                .filter(|before| *before <= sample.value)
+                                .unwrap_or(0.0);
+                            SampleSnapshot {
+                                labels: sample.labels.clone(),
+                                value: sample.value - before,
+                            }
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        MetricsSnapshot { families }
+    }
+
+    pub fn to_metric_families(&self) -> Vec<MetricFamily> {
+        self.families
+            .iter()
+            .filter(|f| !f.samples.is_empty())
+            .map(FamilySnapshot::to_metric_family)
+            .collect()
+    }
+}
+
+impl FamilySnapshot {
+    // sample with exactly these labels, in any order
+    pub fn sample(&self, labels: &[(&str, &str)]) -> Option<&SampleSnapshot> {
+        self.samples.iter().find(|sample| {
+            sample.labels.len() == labels.len()
+                && labels
+                    .iter()
+                    .all(|(k, v)| sample.labels.iter().any(|(sk, sv)| sk == k && sv == v))
+        })
+    }
+
+    pub fn to_metric_family(&self) -> MetricFamily {
+        let mut m = MetricFamily::new();
+        m.set_name(self.name.clone());
+        m.set_help(self.help.clone());
+        m.set_field_type(match self.metric_type {
+            MetricType::Counter => prometheus::proto::MetricType::COUNTER,
+            MetricType::Gauge => prometheus::proto::MetricType::GAUGE,
+        });
+
+        self.samples.iter().for_each(|sample| {
+            let mut metric = Metric::new();
+            match self.metric_type {
+                MetricType::Counter => {
+                    let mut counter = Counter::new();
+                    counter.set_value(sample.value);
+                    metric.set_counter(counter);
+                }
+                MetricType::Gauge => {
+                    let mut gauge = Gauge::new();
+                    gauge.set_value(sample.value);
+                    metric.set_gauge(gauge);
+                }
+            }
+
+            let labels = sample
+                .labels
+                .iter()
+                .map(|(k, v)| {
+                    let mut label = LabelPair::new();
+                    label.set_name(k.to_owned());
+                    label.set_value(v.to_owned());
+                    label
+                })
+                .collect();
+            metric.set_label(RepeatedField::from_vec(labels));
+
+            m.mut_metric().push(metric);
+        });
+
+        m
+    }
+}
+
+// export functions return one family per label set, merge them by name so that every family
+// is only exposed once. only counters and gauges can be registered, a function that starts
+// returning another type after its registration has those families skipped
+impl From<Vec<MetricFamily>> for MetricsSnapshot {
+    fn from(metric_families: Vec<MetricFamily>) -> Self {
+        let mut snapshot = MetricsSnapshot::default();
+
+        for mf in metric_families {
+            let metric_type = match mf.get_field_type() {
+                prometheus::proto::MetricType::COUNTER => MetricType::Counter,
+                prometheus::proto::MetricType::GAUGE => MetricType::Gauge,
+                _ => continue,
+            };
+
+            let samples = mf.get_metric().iter().map(|metric| SampleSnapshot {
+                labels: metric
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name().to_owned(), l.get_value().to_owned()))
+                    .collect(),
+                value: match metric_type {
+                    MetricType::Counter => metric.get_counter().get_value(),
+                    MetricType::Gauge => metric.get_gauge().get_value(),
+                },
+            });
+
+            match snapshot
+                .families
+                .iter_mut()
+                .find(|f| f.name == mf.get_name())
+            {
+                Some(family) => {
+                    if family.help.is_empty() {
+                        family.help = mf.get_help().to_owned();
+                    }
+                    family.samples.extend(samples);
+                }
+                None => snapshot.families.push(FamilySnapshot {
+                    name: mf.get_name().to_owned(),
+                    help: mf.get_help().to_owned(),
+                    metric_type,
+                    source: None,
+                    samples: samples.collect(),
+                }),
+            }
+        }
+
+        snapshot
+    }
+}
+
+// family built by hand for the tests, each sample is given as its labels and value
+#[cfg(test)]
+pub(crate) fn family(
+    name: &str,
+    metric_type: MetricType,
+    samples: &[(&[(&str, &str)], f64)],
+) -> FamilySnapshot {
+    FamilySnapshot {
+        name: name.to_owned(),
+        help: String::new(),
+        metric_type,
+        source: None,
+        samples: samples
+            .iter()
+            .map(|(labels, value)| SampleSnapshot {
+                labels: labels
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                value: *value,
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labeled(name: &str, metric_type: MetricType, value: f64) -> MetricFamily {
+        family(name, metric_type, &[(&[("k", name)], value)]).to_metric_family()
+    }
+
+    #[test]
+    fn families_merged_by_name() {
+        let mut requests = labeled("requests", MetricType::Counter, 1.0);
+        requests.set_help("handled requests".to_owned());
+        let mut summary = MetricFamily::new();
+        summary.set_name("latency".to_owned());
+        summary.set_field_type(prometheus::proto::MetricType::SUMMARY);
+
+        let snapshot = MetricsSnapshot::from(vec![
+            labeled("requests", MetricType::Counter, 2.0),
+            labeled("queue", MetricType::Gauge, -1.0),
+            requests,
+            summary,
+        ]);
+
+        assert_eq!(snapshot.families.len(), 2);
+        let requests = snapshot.family("requests").unwrap();
+        assert_eq!(requests.metric_type, MetricType::Counter);
+        assert_eq!(requests.help, "handled requests");
+        assert_eq!(requests.samples.len(), 2);
+        assert_eq!(requests.samples[1].value, 1.0);
+        assert_eq!(
+            snapshot.family("queue").unwrap().sample(&[("k", "queue")]),
+            Some(&SampleSnapshot {
+                labels: vec![("k".to_owned(), "queue".to_owned())],
+                value: -1.0,
+            })
+        );
+    }
+
+    #[test]
+    fn metric_families_round_trip() {
+        let snapshot = MetricsSnapshot {
+            families: vec![
+                family(
+                    "requests",
+                    MetricType::Counter,
+                    &[(&[], 3.0), (&[("a", "1"), ("b", "2")], 4.0)],
+                ),
+                family("queue", MetricType::Gauge, &[(&[], 1.5)]),
+                family("empty", MetricType::Gauge, &[]),
+            ],
+        };
+
+        // families without samples are left out
+        let families = snapshot.to_metric_families();
+        assert_eq!(families.len(), 2);
+        assert_eq!(
+            families[0].get_field_type(),
+            prometheus::proto::MetricType::COUNTER
+        );
+        assert_eq!(families[0].get_metric()[1].get_counter().get_value(), 4.0);
+        assert_eq!(families[1].get_metric()[0].get_gauge().get_value(), 1.5);
+
+        let mut expected = snapshot.clone();
+        expected.families.pop();
+        assert_eq!(MetricsSnapshot::from(families), expected);
+    }
+
+    #[test]
+    fn delta_of_counters() {
+        let previous = MetricsSnapshot {
+            families: vec![
+                family("requests", MetricType::Counter, &[(&[("k", "a")], 5.0)]),
+                family("queue", MetricType::Gauge, &[(&[], 4.0)]),
+            ],
+        };
+        let current = MetricsSnapshot {
+            families: vec![
+                family(
+                    "requests",
+                    MetricType::Counter,
+                    &[(&[("k", "a")], 7.0), (&[("k", "b")], 2.0)],
+                ),
+                family("queue", MetricType::Gauge, &[(&[], 3.0)]),
+            ],
+        };
+
+        let delta = current.delta(&previous);
+        let requests = delta.family("requests").unwrap();
+        assert_eq!(requests.sample(&[("k", "a")]).unwrap().value, 2.0);
+        // a new label set counts from zero
+        assert_eq!(requests.sample(&[("k", "b")]).unwrap().value, 2.0);
+        // gauges are not deltas
+        assert_eq!(delta.family("queue").unwrap().samples[0].value, 3.0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let snapshot = MetricsSnapshot {
+            families: vec![
+                FamilySnapshot {
+                    source: Some("Requests".to_owned()),
+                    ..family("requests", MetricType::Counter, &[(&[("k", "v")], 2.0)])
+                },
+                family("queue_depth", MetricType::Gauge, &[(&[], 3.5)]),
+            ],
+        };
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert!(json.contains(r#""metric_type":"counter""#), "{json}");
+        assert!(json.contains(r#""metric_type":"gauge""#), "{json}");
+        assert_eq!(
+            serde_json::from_str::<MetricsSnapshot>(&json).unwrap(),
+            snapshot
+        );
+
+        // written before the source was added
+        let json = r#"{"families":[{"name":"up","help":"","metric_type":"gauge",
+            "samples":[{"labels":[],"value":1.0}]}]}"#;
+        let snapshot: MetricsSnapshot = serde_json::from_str(json).unwrap();
+        assert_eq!(snapshot.families[0].source, None);
+        assert_eq!(snapshot.families[0].metric_type, MetricType::Gauge);
+        assert!(serde_json::from_str::<MetricsSnapshot>(&json.replace("gauge", "Gauge")).is_err());
+    }
+}
@@ -0,0 +1,369 @@
+use crate::{
+    delta::DeltaReader,
+    push::{self, PushHandle},
+    registry::Registry,
+    snapshot::{FamilySnapshot, MetricsSnapshot, SampleSnapshot},
+    types::MetricType,
+};
+use std::{
+    io::{Error, Result},
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+// line format sent to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsdFormat {
+    // plain statsd has no tags, label values are appended to the metric name:
+    // requests.status.500:1|c
+    Statsd,
+    // labels are sent as tags: requests:1|c|#status:500
+    DogStatsd,
+}
+
+pub struct StatsdExporter;
+
+// state of the flush thread
+struct StatsdFlush {
+    builder: StatsdBuilder,
+    socket: UdpSocket,
+    reader: DeltaReader,
+}
+
+pub struct StatsdBuilder {
+    target: SocketAddr,
+    registry: Arc<Registry>,
+    format: StatsdFormat,
+    prefix: Option<String>,
+    flush_interval: Duration,
+    max_packet_size: usize,
+    thread_name: String,
+}
+
+impl StatsdExporter {
+    // push the global registry to a statsd server listening on udp
+    pub fn builder(target: SocketAddr) -> StatsdBuilder {
+        StatsdBuilder {
+            target,
+            registry: Registry::global(),
+            format: StatsdFormat::Statsd,
+            prefix: None,
+            flush_interval: Duration::from_secs(10),
+            // fits in an ethernet frame with ip and udp headers
+            max_packet_size: 1432,
+            thread_name: String::from("metrics-statsd"),
+        }
+    }
+}
+
+impl StatsdBuilder {
+    pub fn registry(mut self, registry: Arc<Registry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    pub fn format(mut self, format: StatsdFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    // prepended to every metric name with a dot
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_owned());
+        self
+    }
+
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    // lines are batched in packets up to this size, a single line longer than it is sent alone
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        self.max_packet_size = size;
+        self
+    }
+
+    pub fn thread_name(mut self, name: &str) -> Self {
+        self.thread_name = name.to_owned();
+        self
+    }
+
+    pub fn start(self) -> Result<PushHandle> {
+        let thread_name = self.thread_name.clone();
+        let flush_interval = self.flush_interval;
+        let mut flush = self.connect()?;
+
+        push::spawn(&thread_name, flush_interval, move |_| flush.flush())
+    }
+
+    fn connect(self) -> Result<StatsdFlush> {
+        let local: SocketAddr = if self.target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)
+            .and_then(|socket| socket.connect(self.target).map(|_| socket))
+            .map_err(|e| Error::new(e.kind(), format!("Can't open statsd socket: {e}")))?;
+
+        Ok(StatsdFlush {
+            reader: DeltaReader::new(self.registry.clone()),
+            builder: self,
+            socket,
+        })
+    }
+
+    fn lines(&self, snapshot: &MetricsSnapshot) -> Vec<String> {
+        let mut lines = vec![];
+        for family in &snapshot.families {
+            for sample in &family.samples {
+                self.push_lines(&mut lines, family, sample);
+            }
+        }
+        lines
+    }
+
+    fn push_lines(
+        &self,
+        lines: &mut Vec<String>,
+        family: &FamilySnapshot,
+        sample: &SampleSnapshot,
+    ) {
+        if !sample.value.is_finite() {
+            return;
+        }
+        // counters are sent as deltas, nothing happened
+        if family.metric_type == MetricType::Counter && sample.value == 0.0 {
+            return;
+        }
+
+        let mut name = match &self.prefix {
+            Some(prefix) => format!(
+                "{}.{}",
+                sanitize(prefix, true),
+                sanitize(&family.name, false)
+            ),
+            None => sanitize(&family.name, false),
+        };
+        let mut tags = String::new();
+        match self.format {
+            StatsdFormat::Statsd => sample.labels.iter().for_each(|(k, v)| {
+                name.push_str(&format!(".{}.{}", sanitize(k, false), sanitize(v, false)));
+            }),
+            StatsdFormat::DogStatsd => {
+                if !sample.labels.is_empty() {
+                    tags.push_str("|#");
+                    let labels: Vec<String> = sample
+                        .labels
+                        .iter()
+                        .map(|(k, v)| format!("{}:{}", sanitize(k, true), sanitize(v, true)))
+                        .collect();
+                    tags.push_str(&labels.join(","));
+                }
+            }
+        }
+
+        match family.metric_type {
+            MetricType::Counter => lines.push(format!("{name}:{}|c{tags}", sample.value)),
+            MetricType::Gauge => {
+                // statsd takes a signed gauge value as a change of the current one, it has to be
+                // reset to zero first
+                if self.format == StatsdFormat::Statsd && sample.value < 0.0 {
+                    lines.push(format!("{name}:0|g{tags}"));
+                }
+                lines.push(format!("{name}:{}|g{tags}", sample.value));
+            }
+        }
+    }
+}
+
+impl StatsdFlush {
+    fn flush(&mut self) {
+        if let Ok(snapshot) = self.reader.collect() {
+            let lines = self.builder.lines(&snapshot);
+            // a server that is down must not stop the next flushes
+            packets(&lines, self.builder.max_packet_size)
+                .iter()
+                .for_each(|packet| {
+                    let _ = self.socket.send(packet.as_bytes());
+                });
+        }
+    }
+}
+
+// newline separated lines, as many as fit in each packet
+fn packets(lines: &[String], max_packet_size: usize) -> Vec<String> {
+    let mut packets = vec![];
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > max_packet_size {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+// characters of the statsd line syntax can't appear in names and tags. dots separate the
+// segments of a name, they are kept in the prefix and tags only
+fn sanitize(s: &str, keep_dots: bool) -> String {
+    s.chars()
+        .map(|c| match c {
+            ':' | '|' | '@' | '#' | ',' => '_',
+            '.' if !keep_dots => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::family;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // counter requests{status="500"} read from the returned total, an unused counter and a
+    // negative gauge
+    fn registry() -> (Arc<Registry>, Arc<AtomicU64>) {
+        let registry = Arc::new(Registry::new());
+        let total = Arc::new(AtomicU64::new(5));
+        let requests = total.clone();
+        registry
+            .register(move || {
+                let total = requests.load(Ordering::Relaxed) as f64;
+                vec![
+                    family(
+                        "requests",
+                        MetricType::Counter,
+                        &[(&[("status", "500")], total)],
+                    )
+                    .to_metric_family(),
+                    family("errors", MetricType::Counter, &[(&[], 0.0)]).to_metric_family(),
+                ]
+            })
+            .unwrap();
+        registry
+            .gauge_fn("queue.depth", "help", &[], || -2.0)
+            .unwrap();
+        (registry, total)
+    }
+
+    fn receiver() -> UdpSocket {
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket
+    }
+
+    fn recv(socket: &UdpSocket) -> Vec<String> {
+        let mut buffer = [0; 1500];
+        let len = socket.recv(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..len])
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn statsd_deltas() {
+        let (registry, total) = registry();
+        let receiver = receiver();
+        let mut flush = StatsdExporter::builder(receiver.local_addr().unwrap())
+            .registry(registry)
+            .prefix("app.api")
+            .connect()
+            .unwrap();
+
+        // zero counters are skipped, negative gauges are reset first
+        flush.flush();
+        assert_eq!(
+            recv(&receiver),
+            [
+                "app.api.requests.status.500:5|c",
+                "app.api.queue_depth:0|g",
+                "app.api.queue_depth:-2|g",
+            ]
+        );
+
+        total.store(8, Ordering::Relaxed);
+        flush.flush();
+        assert_eq!(
+            recv(&receiver),
+            [
+                "app.api.requests.status.500:3|c",
+                "app.api.queue_depth:0|g",
+                "app.api.queue_depth:-2|g",
+            ]
+        );
+
+        // no increase, only the gauges are sent
+        flush.flush();
+        assert_eq!(
+            recv(&receiver),
+            ["app.api.queue_depth:0|g", "app.api.queue_depth:-2|g"]
+        );
+    }
+
+    #[test]
+    fn dogstatsd_tags() {
+        let (registry, total) = registry();
+        let receiver = receiver();
+        let mut flush = StatsdExporter::builder(receiver.local_addr().unwrap())
+            .registry(registry)
+            .format(StatsdFormat::DogStatsd)
+            .connect()
+            .unwrap();
+
+        flush.flush();
+        assert_eq!(
+            recv(&receiver),
+            ["requests:5|c|#status:500", "queue_depth:-2|g"]
+        );
+
+        total.store(6, Ordering::Relaxed);
+        flush.flush();
+        assert_eq!(
+            recv(&receiver),
+            ["requests:1|c|#status:500", "queue_depth:-2|g"]
+        );
+    }
+
+    #[test]
+    fn flush_on_drop() {
+        let (registry, _) = registry();
+        let receiver = receiver();
+        let handle = StatsdExporter::builder(receiver.local_addr().unwrap())
+            .registry(registry)
+            .format(StatsdFormat::DogStatsd)
+            .flush_interval(Duration::from_secs(3600))
+            .start()
+            .unwrap();
+
+        drop(handle);
+        assert_eq!(
+            recv(&receiver),
+            ["requests:5|c|#status:500", "queue_depth:-2|g"]
+        );
+    }
+
+    #[test]
+    fn packet_size() {
+        let lines: Vec<String> = ["a:1|c", "b:2|c", "c:3|c"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        assert_eq!(packets(&lines, 11), ["a:1|c\nb:2|c", "c:3|c"]);
+        assert_eq!(packets(&lines, 4), ["a:1|c", "b:2|c", "c:3|c"]);
+        assert_eq!(packets(&[], 100), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{registry::Registry, snapshot::MetricsSnapshot, types::MetricType};
+use std::sync::Arc;
+
+// helpers to check metrics from unit tests without scraping an exporter, behind the "testing"
+// feature:
+//
+//     [dev-dependencies]
+//     metrics_lockfree = { version = "0.1", features = ["testing"] }
+//
+//     let registry = metrics_lockfree::testing::registry();
+//     let mut metrics = MyMetrics::new_in(&registry).unwrap();
+//     let capture = Capture::start(&registry);
+//     handle_request(&mut metrics);
+//     assert_counter!(capture, "requests", [("status", "500")], 1);
+
+// fresh registry for a test, structs built in it with `new_in` don't see the other tests. the
+// factories of those structs are freed with the registry, the thread handles keep their own
+// values alive
+pub fn registry() -> Arc<Registry> {
+    Arc::new(Registry::new())
+}
+
+// what the assert macros can read values from
+pub trait SnapshotSource {
+    fn metrics_snapshot(&self) -> MetricsSnapshot;
+}
+
+impl SnapshotSource for Registry {
+    fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.snapshot().expect("can't snapshot registry")
+    }
+}
+
+impl SnapshotSource for MetricsSnapshot {
+    fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.clone()
+    }
+}
+
+impl<T: SnapshotSource + ?Sized> SnapshotSource for Arc<T> {
+    fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.as_ref().metrics_snapshot()
+    }
+}
+
+// values of a registry when the capture started, reading from it gives the counters increase
+// since then
+pub struct Capture<'a> {
+    registry: &'a Registry,
+    before: MetricsSnapshot,
+}
+
+impl<'a> Capture<'a> {
+    pub fn start(registry: &'a Registry) -> Self {
+        Self {
+            registry,
+            before: registry.metrics_snapshot(),
+        }
+    }
+
+    pub fn delta(&self) -> MetricsSnapshot {
+        self.registry.metrics_snapshot().delta(&self.before)
+    }
+}
+
+impl SnapshotSource for Capture<'_> {
+    fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.delta()
+    }
+}
+
+// used by the assert macros
+#[track_caller]
+pub fn assert_value<S: SnapshotSource + ?Sized>(
+    source: &S,
+    metric_type: MetricType,
+    name: &str,
+    labels: &[(&str, &str)],
+    expected: f64,
+) {
+    let snapshot = source.metrics_snapshot();
+
+    let family = match snapshot.family(name) {
+        Some(family) => family,
+        None => panic!(
+            "metric {name} not found, registered metrics: {:?}",
+            snapshot
+                .families
+                .iter()
+                .map(|f| f.name.as_str())
+                .collect::<Vec<_>>()
+        ),
+    };
+
+    if family.metric_type != metric_type {
+        panic!(
+            "metric {name} is a {:?}, not a {metric_type:?}",
+            family.metric_type
+        );
+    }
+
+    match family.sample(labels) {
+        Some(sample) => {
+            if sample.value != expected {
+                panic!(
+                    "metric {name}{labels:?} is {}, expected {expected}",
+                    sample.value
+                );
+            }
+        }
+        None => panic!(
+            "metric {name} has no label set {labels:?}, label sets: {:?}",
+            family.samples.iter().map(|s| &s.labels).collect::<Vec<_>>()
+        ),
+    }
+}
+
+// assert_counter!(source, "name", 1) or assert_counter!(source, "name", [("k", "v")], 1), the
+// source is a registry, a snapshot or a capture
+#[macro_export]
+macro_rules! assert_counter {
+    ($source:expr, $name:expr, [$(($k:expr, $v:expr)),* $(,)?], $value:expr) => {
+        $crate::testing::assert_value(
+            &$source,
+            $crate::types::MetricType::Counter,
+            $name,
+            &[$(($k, $v)),*],
+            $value as f64,
+        )
+    };
+    ($source:expr, $name:expr, $value:expr) => {
+        $crate::assert_counter!($source, $name, [], $value)
+    };
+}
+
+#[macro_export]
+macro_rules! assert_gauge {
+    ($source:expr, $name:expr, [$(($k:expr, $v:expr)),* $(,)?], $value:expr) => {
+        $crate::testing::assert_value(
+            &$source,
+            $crate::types::MetricType::Gauge,
+            $name,
+            &[$(($k, $v)),*],
+            $value as f64,
+        )
+    };
+    ($source:expr, $name:expr, $value:expr) => {
+        $crate::assert_gauge!($source, $name, [], $value)
+    };
+}
@@ -1,6 +1,5 @@
 extern crate proc_macro;
 
-use heck::ToSnakeCase;
 use proc_macro2::{Ident, Span, TokenStream};
 use std::env;
 use syn::{Data, DeriveInput, Fields};
@@ -25,23 +24,6 @@ fn non_struct_error() -> syn::Error {
     syn::Error::new(Span::call_site(), "This macro only supports structs.")
 }
 
-/// heck doesn't treat numbers as new words, but this function does.
-/// E.g. for input `Hello2You`, heck would output `hello2_you`, and snakify would output `hello_2_you`.
-fn snakify(s: &str) -> String {
-    let mut output: Vec<char> = s.to_string().to_snake_case().chars().collect();
-    let mut num_starts = vec![];
-    for (pos, c) in output.iter().enumerate() {
-        if c.is_ascii_digit() && pos != 0 && !output[pos - 1].is_ascii_digit() {
-            num_starts.push(pos);
-        }
-    }
-    // need to do in reverse, because after inserting, all chars after the point of insertion are off
-    for i in num_starts.into_iter().rev() {
-        output.insert(i, '_')
-    }
-    output.into_iter().collect()
-}
-
 /*
 fn parse_field_doc_comment(attrs: &[syn::Attribute]) -> Option<String> {
     for attr in attrs {
@@ -61,28 +43,57 @@ fn parse_field_doc_comment(attrs: &[syn::Attribute]) -> Option<String> {
 }
 */
 
-fn generate_impl_user_struct(user_struct_name: &Ident, static_factory_name: &Ident) -> TokenStream {
+fn generate_impl_user_struct(user_struct_name: &Ident, factory_struct_name: &Ident) -> TokenStream {
+    let user_struct_name_str = user_struct_name.to_string();
+
     quote! {
         unsafe impl Send for #user_struct_name {}
 
-        impl MyMetrics {
-            pub fn new() -> Option<#user_struct_name> {
-                if let Ok(mut factory) = #static_factory_name.write() {
-                    Some(factory.build())
-                } else {
-                    None
-                }
+        impl #user_struct_name {
+            // thread handle registered in the global registry
+            pub fn new() -> std::io::Result<#user_struct_name> {
+                Self::new_in(&metrics_lockfree::registry::Registry::global())
+            }
+
+            // fails if a field has the name of a metric already registered by something else
+            pub fn new_in(registry: &metrics_lockfree::registry::Registry) -> std::io::Result<#user_struct_name> {
+                let factory = registry.struct_factory(
+                    #user_struct_name_str,
+                    #factory_struct_name::new,
+                    #factory_struct_name::metrics,
+                    #factory_struct_name::thread_count,
+                )?;
+                let built = match factory.write() {
+                    Ok(mut factory) => Ok(factory.build()),
+                    Err(e) => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Poison error: {e}"),
+                    )),
+                };
+                built
+            }
+
+            // current values summed over the thread handles built in the global registry
+            pub fn snapshot() -> metrics_lockfree::snapshot::MetricsSnapshot {
+                Self::snapshot_in(&metrics_lockfree::registry::Registry::global())
+            }
+
+            // empty when no thread handle was built in the registry
+            pub fn snapshot_in(registry: &metrics_lockfree::registry::Registry) -> metrics_lockfree::snapshot::MetricsSnapshot {
+                registry
+                    .find_struct_factory::<#factory_struct_name>()
+                    .and_then(|factory| {
+                        let snapshot = factory.read().ok().map(|factory| factory.snapshot());
+                        snapshot
+                    })
+                    .unwrap_or_default()
             }
         }
     }
 }
 
-fn generate_tags_global_fn(user_struct_name: &Ident, field_name: &Ident) -> Ident {
-    format_ident!(
-        "{}_{}_tags_get",
-        snakify(user_struct_name.to_string().as_str()),
-        field_name
-    )
+fn generate_tags_field(field_name: &Ident) -> Ident {
+    format_ident!("{}_tags", field_name)
 }
 
 fn generate_factory(
@@ -90,15 +101,19 @@ fn generate_factory(
     user_struct_name: &Ident,
     values_struct_name: &Ident,
     factory_struct_name: &Ident,
-    static_factory_name: &Ident,
 ) -> TokenStream {
-    let mut metrics = vec![];
-    let mut metrics_tags_hashmap = vec![];
-
-    let metrics_tags_static_prefix = format_ident!(
-        "{}",
-        format!("{}_TAGS_HASHMAP_", user_struct_name).to_uppercase()
-    );
+    let user_struct_name_str = user_struct_name.to_string();
+    let mut families = vec![];
+    let mut tags_types = vec![];
+    let mut tags_init = vec![];
+    // handles are built from the values before they are shared, then given the values to keep
+    // them alive
+    let mut field_init = vec![];
+    let mut field_keep = vec![];
+    // counters of the retired thread handles are added to the factory totals
+    let mut field_retire = vec![];
+    // computed gauge fields, only there to declare the metric
+    let mut placeholders = vec![];
 
     for field in fields {
         let ident = if let Some(ident) = &field.ident {
@@ -108,143 +123,241 @@ fn generate_factory(
         };
         let ident_str = ident.to_string();
 
-        let ty = MacroFieldType::from(&field.ty);
+        let ty = MacroFieldType::from(field);
 
         // fill types
         match ty {
-            MacroFieldType::Gauge => {
-                metrics.push(quote! {
-                    let mut value_sum = 0;
-                    factory.threads().iter().for_each(|f| {
-                        value_sum += f.#ident.get();
+            MacroFieldType::Computed(path) => {
+                // nothing stored per thread, the value is computed at scrape time
+                field_init.push(quote!(let #ident = metrics_lockfree::gauge::GaugeFn;));
+                field_keep.push(quote!(#ident));
+                placeholders.push(quote!(let _ = &self.#ident;));
+                families.push(quote! {
+                    families.push(metrics_lockfree::snapshot::FamilySnapshot {
+                        name: #ident_str.to_owned(),
+                        help: String::new(),
+                        metric_type: metrics_lockfree::types::MetricType::Gauge,
+                        source: Some(#user_struct_name_str.to_owned()),
+                        samples: vec![metrics_lockfree::snapshot::SampleSnapshot {
+                            labels: vec![],
+                            value: #path(),
+                        }],
                     });
-
-                    metrics.push(metrics_lockfree::prometheus::prometheus_metric_family_build(
-                        metrics_lockfree::types::MetricType::Gauge,
-                        #ident_str,
-                        value_sum,
-                        None,
-                    ));
                 });
             }
-            MacroFieldType::Counter(max_tags) => {
-                let static_hashmap_name = format_ident!(
-                    "{}_{}",
-                    metrics_tags_static_prefix,
-                    ident.to_string().to_uppercase()
-                );
-
-                metrics.push(quote! {
+            MacroFieldType::Gauge => {
+                field_init
+                    .push(quote!(let #ident = metrics_lockfree::gauge::Gauge::from(&mut value.#ident);));
+                field_keep.push(quote!(#ident: #ident.keep_alive(value.clone())));
+                families.push(quote! {
+                    // nothing updates the gauge of a retired thread handle anymore
                     let mut value_sum = 0;
-                    factory.threads().iter().for_each(|f| {
-                        value_sum += f.#ident.get(0);
-                    });
-
-                    metrics.push(metrics_lockfree::prometheus::prometheus_metric_family_build(
-                        metrics_lockfree::types::MetricType::Counter,
-                        #ident_str,
-                        value_sum,
-                        None,
-                    ));
-
-
-                    // then other tags
-                    #static_hashmap_name
-                        .read()
-                        .unwrap()
-                        .tags()
+                    self.threads()
                         .iter()
-                        .for_each(|(key_value, id)| {
-                            let mut value_sum_tag = 0;
-                            factory.threads().iter().for_each(|f| {
-                                value_sum_tag += f.#ident.get(*id);
-                            });
-
-                            println!("{key_value:?}, {id:?}");
-
-                            metrics.push(metrics_lockfree::prometheus::prometheus_metric_family_build(
-                                metrics_lockfree::types::MetricType::Counter,
-                                #ident_str,
-                                value_sum_tag,
-                                Some(key_value),
-                            ));
+                        .filter(|f| std::sync::Arc::strong_count(f) > 1)
+                        .for_each(|f| {
+                            value_sum += f.#ident.get();
                         });
 
+                    families.push(metrics_lockfree::snapshot::FamilySnapshot {
+                        name: #ident_str.to_owned(),
+                        help: String::new(),
+                        metric_type: metrics_lockfree::types::MetricType::Gauge,
+                        source: Some(#user_struct_name_str.to_owned()),
+                        samples: vec![metrics_lockfree::snapshot::SampleSnapshot {
+                            labels: vec![],
+                            value: value_sum as f64,
+                        }],
+                    });
                 });
+            }
+            MacroFieldType::Counter(max_tags) => {
+                let tags_name = generate_tags_field(ident);
 
-                let fn_name = generate_tags_global_fn(user_struct_name, ident);
-
-                metrics_tags_hashmap.push(quote! {
-                    static #static_hashmap_name: std::sync::LazyLock<std::sync::RwLock<metrics_lockfree::types::Tags>> =
-                        std::sync::LazyLock::new(|| std::sync::RwLock::new(metrics_lockfree::types::Tags::new(#max_tags)));
-
-                    pub fn #fn_name(tags: &[(String, String)]) -> Option<usize> {
-                        if let Some(id) = #static_hashmap_name.read().unwrap().get(tags) {
-                            return Some(id);
-                        }
-                        #static_hashmap_name.write().unwrap().insert(tags)
+                tags_types.push(quote! {
+                    #tags_name: std::sync::Arc<std::sync::RwLock<metrics_lockfree::types::Tags>>
+                });
+                tags_init.push(quote! {
+                    #tags_name: std::sync::Arc::new(std::sync::RwLock::new(metrics_lockfree::types::Tags::new(#max_tags)))
+                });
+                field_init.push(quote! {
+                    let #ident = metrics_lockfree::counter::Counter::from(&mut value.#ident).set_tags(self.#tags_name.clone());
+                });
+                field_keep.push(quote!(#ident: #ident.keep_alive(value.clone())));
+                field_retire.push(quote!(self.retired.#ident.merge(&value.#ident);));
+
+                families.push(quote! {
+                    // tagless value first, then tags in allocation order
+                    let mut ids = vec![(vec![], 0)];
+                    if let Ok(tags) = self.#tags_name.read() {
+                        let mut tags: Vec<_> = tags
+                            .tags()
+                            .iter()
+                            .map(|(key_value, id)| (key_value.clone(), *id))
+                            .collect();
+                        tags.sort_unstable_by_key(|(_, id)| *id);
+                        ids.extend(tags);
                     }
+
+                    families.push(metrics_lockfree::snapshot::FamilySnapshot {
+                        name: #ident_str.to_owned(),
+                        help: String::new(),
+                        metric_type: metrics_lockfree::types::MetricType::Counter,
+                        source: Some(#user_struct_name_str.to_owned()),
+                        samples: ids
+                            .into_iter()
+                            .map(|(labels, id)| {
+                                let mut value_sum = self.retired.#ident.get(id);
+                                self.threads().iter().for_each(|f| {
+                                    value_sum += f.#ident.get(id);
+                                });
+
+                                metrics_lockfree::snapshot::SampleSnapshot {
+                                    labels,
+                                    value: value_sum as f64,
+                                }
+                            })
+                            .collect(),
+                    });
                 });
             }
             MacroFieldType::Unknown(s) => panic!(
-                "Error: field '{}' has invalid type: '{s}'. It must be 'Counter' or 'Gauge'",
+                "Error: field '{}' has invalid type: '{s}'. It must be 'Counter', 'Gauge' or 'GaugeFn'",
                 ident
             ),
         };
     }
 
     quote! {
+        impl #user_struct_name {
+            // the placeholder fields are never read, keep them from being reported as dead code
+            #[allow(dead_code)]
+            fn computed_gauge_fields(&self) {
+                #(#placeholders)*
+            }
+        }
 
         struct #factory_struct_name {
-            per_thread_metrics: Vec<#values_struct_name>,
+            // shared with the thread handles pointing to them
+            per_thread_metrics: Vec<std::sync::Arc<#values_struct_name>>,
+            // counter totals of the thread handles that were dropped, no handle points to them
+            retired: #values_struct_name,
+            // tags allocated by the counters of every thread handle
+            #(#tags_types),*
         }
 
         impl #factory_struct_name {
             pub fn new() -> Self {
-                metrics_lockfree::Exporter::register(#factory_struct_name::metrics);
                 Self {
                     per_thread_metrics: vec![],
+                    retired: #values_struct_name::default(),
+                    #(#tags_init),*
                 }
             }
 
             pub fn build(&mut self) -> #user_struct_name {
-                // todo push per type
+                self.retire();
+
+                // the values are pinned, the handles still point to them once moved in the arc
+                let mut value = #values_struct_name::default();
+                #(#field_init)*
+                let value = std::sync::Arc::new(value);
+                self.per_thread_metrics.push(value.clone());
+                #user_struct_name {
+                    #(#field_keep),*
+                }
+            }
 
-                self.per_thread_metrics.push(#values_struct_name::default());
-                let last = self.per_thread_metrics.last_mut().unwrap();
-                #user_struct_name::from(last)
+            // the values only owned by the factory belong to dropped thread handles: their
+            // counters are added to the retired totals, so that they never go down, and their
+            // gauges are dropped. the handles don't write to them anymore
+            fn retire(&mut self) {
+                let threads = std::mem::take(&mut self.per_thread_metrics);
+                for values in threads {
+                    match std::sync::Arc::try_unwrap(values) {
+                        Ok(value) => self.retire_values(&value),
+                        Err(values) => self.per_thread_metrics.push(values),
+                    }
+                }
             }
 
-            pub fn threads(&self) -> &Vec<#values_struct_name> {
+            #[allow(unused_variables)]
+            fn retire_values(&mut self, value: &#values_struct_name) {
+                #(#field_retire)*
+            }
+
+            // may include values of handles dropped since the last build, until they are retired
+            pub fn threads(&self) -> &Vec<std::sync::Arc<#values_struct_name>> {
                 &self.per_thread_metrics
             }
 
-            pub fn metrics() -> Vec<prometheus::proto::MetricFamily> {
-                let mut metrics = vec![];
+            // thread handles alive
+            pub fn thread_count(&self) -> usize {
+                self.per_thread_metrics
+                    .iter()
+                    .filter(|f| std::sync::Arc::strong_count(f) > 1)
+                    .count()
+            }
 
-                if let Ok(factory) = #static_factory_name.read() {
+            // summed values of every thread handle
+            pub fn snapshot(&self) -> metrics_lockfree::snapshot::MetricsSnapshot {
+                let mut families = vec![];
 
-                    #(#metrics)*
-                }
+                #(#families)*
 
-                metrics
+                metrics_lockfree::snapshot::MetricsSnapshot { families }
             }
 
+            pub fn metrics(&self) -> Vec<prometheus::proto::MetricFamily> {
+                self.snapshot().to_metric_families()
+            }
         }
-
-        static #static_factory_name : std::sync::LazyLock<std::sync::RwLock<#factory_struct_name>> =
-            std::sync::LazyLock::new(|| std::sync::RwLock::new(#factory_struct_name ::new()));
-
-        #(#metrics_tags_hashmap)*
     }
 }
 
 enum MacroFieldType {
     Gauge,
     Counter(usize),
+    // GaugeFn field, with the path of the `fn() -> f64` computing it
+    Computed(syn::Path),
     Unknown(String),
 }
 
+impl From<&syn::Field> for MacroFieldType {
+    fn from(field: &syn::Field) -> Self {
+        let mut compute = None;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("metric")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("compute") {
+                    let path: syn::LitStr = meta.value()?.parse()?;
+                    compute = Some(path.parse::<syn::Path>()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported metric attribute, expected 'compute'"))
+                }
+            })
+            .unwrap_or_else(|e| panic!("Error: invalid #[metric] attribute: {e}"));
+        }
+
+        let ty = MacroFieldType::from(&field.ty);
+        match (compute, ty) {
+            (Some(path), MacroFieldType::Unknown(s)) if s == "GaugeFn" => {
+                MacroFieldType::Computed(path)
+            }
+            (None, MacroFieldType::Unknown(s)) if s == "GaugeFn" => {
+                panic!(
+                    "Error: GaugeFn field needs a #[metric(compute = \"path::to_fn\")] attribute"
+                )
+            }
+            (Some(_), _) => panic!(
+                "Error: #[metric(compute)] can only be used on a 'GaugeFn' field, not '{}'",
+                field.ty.to_token_stream()
+            ),
+            (None, ty) => ty,
+        }
+    }
+}
+
 impl From<&syn::Type> for MacroFieldType {
     fn from(value: &syn::Type) -> Self {
         match value {
@@ -279,13 +392,8 @@ impl From<&syn::Type> for MacroFieldType {
     }
 }
 
-fn generate_struct_values(
-    fields: &Fields,
-    user_struct_name: &Ident,
-    values_struct_name: &Ident,
-) -> TokenStream {
+fn generate_struct_values(fields: &Fields, values_struct_name: &Ident) -> TokenStream {
     let mut field_types = vec![];
-    let mut field_init = vec![];
 
     for field in fields {
         let ident = if let Some(ident) = &field.ident {
@@ -294,25 +402,20 @@ fn generate_struct_values(
             continue;
         };
 
-        let ty = MacroFieldType::from(&field.ty);
+        let ty = MacroFieldType::from(field);
 
         // fill types
         match ty {
+            // nothing stored per thread, the value is computed at scrape time
+            MacroFieldType::Computed(_) => {}
             MacroFieldType::Gauge => {
                 field_types.push(quote!(#ident: metrics_lockfree::gauge::GaugePin));
-                field_init
-                    .push(quote!(#ident: metrics_lockfree::gauge::Gauge::from(&mut value.#ident)));
             }
             MacroFieldType::Counter(max_tags) => {
-                let fn_name = generate_tags_global_fn(user_struct_name, ident);
-
                 field_types.push(quote!(#ident: metrics_lockfree::counter::CounterPin<#max_tags>));
-                field_init.push(
-                    quote!(#ident: metrics_lockfree::counter::Counter::from(&mut value.#ident).set_fn(#fn_name)),
-                );
             }
             MacroFieldType::Unknown(s) => panic!(
-                "Error: field '{}' has invalid type: '{s}'. It must be 'Counter' or 'Gauge'",
+                "Error: field '{}' has invalid type: '{s}'. It must be 'Counter', 'Gauge' or 'GaugeFn'",
                 ident
             ),
         };
@@ -320,17 +423,9 @@ fn generate_struct_values(
 
     quote! {
         #[derive(Default)]
-        pub struct MyMetricsValues {
+        pub struct #values_struct_name {
             #(#field_types),*
         }
-
-        impl From<&mut #values_struct_name> for #user_struct_name {
-            fn from(value: &mut #values_struct_name) -> Self {
-                Self {
-                    #(#field_init),*
-                }
-            }
-        }
     }
 }
 
@@ -340,21 +435,19 @@ fn generate_metrics(ast: &DeriveInput) -> syn::Result<TokenStream> {
         _ => return Err(non_struct_error()),
     };
 
-    let static_factory_name = format_ident!("{}", format!("{}Factory", &ast.ident).to_uppercase());
     let factory_struct_name = format_ident!("{}Factory", &ast.ident);
     let values_struct_name = format_ident!("{}Values", &ast.ident);
     let user_struct_name = ast.ident.clone();
 
-    let impl_user_struct = generate_impl_user_struct(&user_struct_name, &static_factory_name);
+    let impl_user_struct = generate_impl_user_struct(&user_struct_name, &factory_struct_name);
 
-    let struct_values = generate_struct_values(fields, &user_struct_name, &values_struct_name);
+    let struct_values = generate_struct_values(fields, &values_struct_name);
 
     let factory = generate_factory(
         fields,
         &user_struct_name,
         &values_struct_name,
         &factory_struct_name,
-        &static_factory_name,
     );
 
     Ok(quote! {
@@ -366,7 +459,7 @@ fn generate_metrics(ast: &DeriveInput) -> syn::Result<TokenStream> {
     })
 }
 
-#[proc_macro_derive(Metrics)]
+#[proc_macro_derive(Metrics, attributes(metric))]
 pub fn enum_try_as(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let ast = syn::parse_macro_input!(input as DeriveInput);
 
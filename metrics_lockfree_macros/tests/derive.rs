@@ -0,0 +1,142 @@
+use metrics_lockfree::{
+    assert_counter, assert_gauge,
+    counter::Counter,
+    delta::DeltaReader,
+    gauge::{Gauge, GaugeFn},
+    registry::Registry,
+    testing::{self, Capture},
+};
+use metrics_lockfree_macros::Metrics;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+#[derive(Metrics)]
+struct Requests {
+    requests: Counter,
+    in_flight: Gauge,
+}
+
+fn value(registry: &Registry, name: &str) -> f64 {
+    Requests::snapshot_in(registry)
+        .family(name)
+        .and_then(|family| family.sample(&[]))
+        .map(|sample| sample.value)
+        .unwrap_or_default()
+}
+
+#[test]
+fn handles_outlive_registry() {
+    let registry = Registry::new();
+    let mut metrics = Requests::new_in(&registry).unwrap();
+    metrics.requests.add(2, None);
+    metrics.in_flight.set(1);
+    assert_eq!(value(&registry, "requests"), 2.0);
+    assert_eq!(value(&registry, "in_flight"), 1.0);
+
+    // the factory is freed with the registry, the handle keeps its own values
+    let factory = Arc::downgrade(&registry.find_struct_factory::<RequestsFactory>().unwrap());
+    drop(registry);
+    assert!(factory.upgrade().is_none());
+    metrics.requests.add(1, None);
+    metrics.in_flight.set(3);
+}
+
+#[test]
+fn testing_registry() {
+    let registry = testing::registry();
+    let mut metrics = Requests::new_in(&registry).unwrap();
+    metrics.requests.add(2, None);
+
+    let capture = Capture::start(&registry);
+    metrics.requests.add(1, None);
+    metrics.in_flight.set(4);
+    assert_counter!(registry, "requests", 3);
+    assert_counter!(capture, "requests", 1);
+    assert_gauge!(capture, "in_flight", 4);
+
+    // nothing is left behind by the registry of a test
+    drop(capture);
+    let factory = Arc::downgrade(&registry.find_struct_factory::<RequestsFactory>().unwrap());
+    drop(registry);
+    assert!(factory.upgrade().is_none());
+}
+
+#[test]
+fn retired_thread() {
+    let registry = testing::registry();
+    let mut delta = DeltaReader::new(registry.clone());
+    let thread_count = || {
+        let factory = registry.find_struct_factory::<RequestsFactory>().unwrap();
+        let count = factory.read().unwrap().thread_count();
+        count
+    };
+
+    let mut first = Requests::new_in(&registry).unwrap();
+    let mut second = Requests::new_in(&registry).unwrap();
+    first.requests.add(2, None);
+    first.in_flight.set(1);
+    second.requests.add(3, None);
+    second.in_flight.set(2);
+    assert_counter!(delta.collect().unwrap(), "requests", 5);
+    assert_eq!(thread_count(), 2);
+
+    // the counter total is kept, the gauge of the dropped handle isn't
+    drop(second);
+    let collected = delta.collect().unwrap();
+    assert_counter!(collected, "requests", 0);
+    assert_gauge!(collected, "in_flight", 1);
+    assert_counter!(registry, "requests", 5);
+    assert_eq!(thread_count(), 1);
+
+    // the slot is retired when the next handle is built
+    let mut third = Requests::new_in(&registry).unwrap();
+    third.requests.add(1, None);
+    assert_counter!(delta.collect().unwrap(), "requests", 1);
+    assert_counter!(registry, "requests", 6);
+    assert_eq!(thread_count(), 2);
+    assert_eq!(
+        registry
+            .find_struct_factory::<RequestsFactory>()
+            .unwrap()
+            .read()
+            .unwrap()
+            .threads()
+            .len(),
+        2
+    );
+    first.requests.add(1, None);
+    assert_counter!(delta.collect().unwrap(), "requests", 1);
+}
+
+static QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+
+fn queue_depth() -> f64 {
+    QUEUE_DEPTH.load(Ordering::Relaxed) as f64
+}
+
+#[derive(Metrics)]
+struct Queue {
+    pushed: Counter,
+    #[metric(compute = "queue_depth")]
+    depth: GaugeFn,
+}
+
+#[test]
+fn computed_gauge() {
+    let registry = testing::registry();
+    let mut metrics = Queue::new_in(&registry).unwrap();
+    metrics.pushed.add(1, None);
+    QUEUE_DEPTH.store(7, Ordering::Relaxed);
+    assert_counter!(registry, "pushed", 1);
+    assert_gauge!(registry, "depth", 7);
+
+    // called again on every collection
+    QUEUE_DEPTH.store(2, Ordering::Relaxed);
+    assert_gauge!(registry, "depth", 2);
+    let snapshot = Queue::snapshot_in(&registry);
+    let family = snapshot.family("depth").unwrap();
+    assert_eq!(family.source.as_deref(), Some("Queue"));
+    assert_eq!(family.sample(&[]).unwrap().value, 2.0);
+}
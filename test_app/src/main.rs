@@ -15,7 +15,7 @@ pub struct MyMetrics {
 
 fn main() {
     let binding = "127.0.0.1:9186".parse().unwrap();
-    metrics_lockfree::Exporter::start(binding).unwrap();
+    let _exporter = metrics_lockfree::Exporter::start(binding).unwrap();
 
     let mut thread1 = MyMetrics::new().unwrap();
     let t1 = spawn(move || loop {